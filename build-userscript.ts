@@ -60,8 +60,10 @@ esbuild
 			js: `// ==UserScript==
 // @name         Arueshalae
 // @version      ${version}
-// @description  Downloads your rule34.xxx favorites
+// @description  Downloads your rule34.xxx, gelbooru.com and safebooru.org favorites
 // @match        https://rule34.xxx/index.php?*
+// @match        https://gelbooru.com/index.php?*
+// @match        https://safebooru.org/index.php?*
 // @grant        GM.xmlHttpRequest
 // @run-at       document-idle
 // ==/UserScript==`,
@@ -0,0 +1,249 @@
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use tokio::task::{JoinError, JoinSet};
+use tracing::{info, warn};
+
+use crate::{
+    database::Database,
+    media_processor::{MediaProbe, file_name, perceptual_hash, probe_media},
+    upload::ContentHashes,
+};
+
+const BATCH_SIZE: i64 = 100;
+
+pub struct BackfillOptions {
+    /// How many posts are processed at the same time.
+    pub jobs: usize,
+    /// Only report what is missing without reading any files.
+    pub dry_run: bool,
+}
+
+/// A post that is missing some of the metadata newer versions store on upload.
+struct IncompletePost {
+    id: i64,
+    external_id: i64,
+    extension: String,
+    original: bool,
+    missing_hashes: bool,
+    missing_phash: bool,
+    missing_probe: bool,
+}
+
+#[derive(Default)]
+struct Backfilled {
+    hashes: Option<ContentHashes>,
+    phash: Option<i64>,
+    file_size: Option<i64>,
+    probe: Option<MediaProbe>,
+}
+
+/// Fills in metadata of posts downloaded before it was stored. Every post is saved as soon as it
+/// is done, so an interrupted run continues where it left off. Posts whose metadata couldn't be
+/// computed keep their empty columns and are retried on the next run.
+pub async fn backfill(
+    database: &Database,
+    base_path: &Utf8Path,
+    options: BackfillOptions,
+) -> Result<()> {
+    let total = database.count_incomplete_posts().await?;
+    info!("{total} posts are missing metadata");
+
+    let mut tasks = JoinSet::new();
+    let mut progress = Progress {
+        total,
+        ..Default::default()
+    };
+    let mut last_id = 0;
+
+    loop {
+        let posts = database.get_incomplete_posts(last_id, BATCH_SIZE).await?;
+        let Some(last) = posts.last() else {
+            break;
+        };
+        last_id = last.id;
+
+        for post in posts {
+            let name = file_name(post.id, post.external_id, &post.extension);
+            if options.dry_run {
+                info!(
+                    "{name}: missing{}{}{}",
+                    if post.missing_hashes { " hashes" } else { "" },
+                    if post.missing_phash { " phash" } else { "" },
+                    if post.missing_probe {
+                        " media info"
+                    } else {
+                        ""
+                    },
+                );
+                continue;
+            }
+
+            if tasks.len() >= options.jobs.max(1) {
+                if let Some(result) = tasks.join_next().await {
+                    progress.record(result);
+                }
+            }
+
+            let database = database.clone();
+            let base_path = base_path.to_path_buf();
+            tasks.spawn(async move {
+                let backfilled = compute(&base_path, &post, &name)
+                    .await
+                    .with_context(|| format!("failed to backfill {name}"))?;
+                database.fill_post_metadata(post.id, &backfilled).await
+            });
+        }
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        progress.record(result);
+    }
+
+    if !options.dry_run {
+        progress.log();
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Progress {
+    total: i64,
+    done: i64,
+    failed: i64,
+}
+
+impl Progress {
+    fn record(&mut self, result: Result<Result<()>, JoinError>) {
+        match result {
+            Ok(Ok(())) => self.done += 1,
+            Ok(Err(err)) => {
+                warn!("{err:#}");
+                self.failed += 1;
+            }
+            Err(err) => {
+                warn!("backfill task failed: {err}");
+                self.failed += 1;
+            }
+        }
+        if (self.done + self.failed) % BATCH_SIZE == 0 {
+            self.log();
+        }
+    }
+
+    fn log(&self) {
+        info!(
+            "Backfilled {}/{} posts ({} failed)",
+            self.done, self.total, self.failed
+        );
+    }
+}
+
+async fn compute(base_path: &Utf8Path, post: &IncompletePost, name: &str) -> Result<Backfilled> {
+    let path = base_path.join(name);
+    let file_size = tokio::fs::metadata(&path)
+        .await
+        .with_context(|| format!("{path} is missing"))?
+        .len() as i64;
+
+    let mut backfilled = Backfilled {
+        file_size: Some(file_size),
+        ..Default::default()
+    };
+
+    // Recompressed files don't match the hashes of the site anymore.
+    if post.missing_hashes && post.original {
+        backfilled.hashes = Some(ContentHashes::of_file(&path).await?);
+    }
+
+    if post.missing_phash {
+        let thumb = base_path.join(".thumbs").join(format!("{name}.jpeg"));
+        let hashed_file = if thumb.is_file() { thumb } else { path.clone() };
+        match perceptual_hash(hashed_file.as_std_path()).await {
+            Ok(phash) => backfilled.phash = Some(phash),
+            Err(err) => warn!("Could not hash {name}: {err:#}"),
+        }
+    }
+
+    if post.missing_probe {
+        match probe_media(path.as_std_path()).await {
+            Ok(probe) => backfilled.probe = Some(probe),
+            Err(err) => warn!("Could not probe {name}: {err:#}"),
+        }
+    }
+
+    Ok(backfilled)
+}
+
+impl Database {
+    async fn count_incomplete_posts(&self) -> Result<i64> {
+        Ok(sqlx::query_scalar!(
+            r#"SELECT COUNT(*)
+            FROM posts
+            WHERE (sha256 IS NULL AND original) OR phash IS NULL OR file_size IS NULL
+                OR width IS NULL"#
+        )
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn get_incomplete_posts(&self, after_id: i64, limit: i64) -> Result<Vec<IncompletePost>> {
+        Ok(sqlx::query_as!(
+            IncompletePost,
+            r#"SELECT id, external_id, extension, original,
+                sha256 IS NULL AND original AS "missing_hashes: bool",
+                phash IS NULL AS "missing_phash: bool",
+                width IS NULL AS "missing_probe: bool"
+            FROM posts
+            WHERE id > ?
+                AND ((sha256 IS NULL AND original) OR phash IS NULL OR file_size IS NULL
+                    OR width IS NULL)
+            ORDER BY id
+            LIMIT ?"#,
+            after_id,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    /// Only fills columns that are still empty, so values stored on upload are never replaced.
+    async fn fill_post_metadata(&self, id: i64, backfilled: &Backfilled) -> Result<()> {
+        let (sha256, md5) = match &backfilled.hashes {
+            Some(hashes) => (Some(&hashes.sha256), Some(&hashes.md5)),
+            None => (None, None),
+        };
+        let probe = backfilled.probe.as_ref();
+        let (width, height, duration, frame_rate, codec) = (
+            probe.and_then(|probe| probe.width),
+            probe.and_then(|probe| probe.height),
+            probe.and_then(|probe| probe.duration),
+            probe.and_then(|probe| probe.frame_rate),
+            probe.and_then(|probe| probe.codec.as_ref()),
+        );
+        let has_audio = probe.map(|probe| probe.has_audio);
+
+        sqlx::query!(
+            r#"UPDATE posts
+            SET sha256 = COALESCE(sha256, ?), md5 = COALESCE(md5, ?), phash = COALESCE(phash, ?),
+                file_size = COALESCE(file_size, ?), width = COALESCE(width, ?),
+                height = COALESCE(height, ?), duration = COALESCE(duration, ?),
+                frame_rate = COALESCE(frame_rate, ?), codec = COALESCE(codec, ?),
+                has_audio = COALESCE(has_audio, CASE WHEN mime LIKE 'video/%' THEN ? END)
+            WHERE id = ?"#,
+            sha256,
+            md5,
+            backfilled.phash,
+            backfilled.file_size,
+            width,
+            height,
+            duration,
+            frame_rate,
+            codec,
+            has_audio,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
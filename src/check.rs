@@ -0,0 +1,210 @@
+use std::collections::HashSet;
+
+use anyhow::{Context, Result, anyhow};
+use camino::{Utf8Path, Utf8PathBuf};
+use tracing::{info, warn};
+
+use crate::{
+    database::Database,
+    media_processor::{file_name, file_type, probe_media, video_thumbnail},
+};
+
+const QUARANTINE_DIR: &str = ".quarantine";
+
+pub struct CheckOptions {
+    /// Fix what can be fixed instead of only reporting it.
+    pub repair: bool,
+}
+
+struct CheckedPost {
+    id: i64,
+    external_id: i64,
+    extension: String,
+    mime: String,
+}
+
+#[derive(Default)]
+struct Report {
+    missing_files: usize,
+    missing_thumbs: usize,
+    mismatched_types: usize,
+    orphaned_files: usize,
+    orphaned_thumbs: usize,
+    stray_minis: usize,
+}
+
+/// Compares the posts in the database with the files in the library. With `repair`, missing video
+/// thumbnails are regenerated, files that don't belong to any post are moved to `.quarantine` and
+/// posts whose file is missing or broken are flagged for re-download.
+///
+/// Repairing while the server runs isn't safe: an upload's file is written before its post is
+/// committed, so it would look orphaned and be quarantined.
+pub async fn check(database: &Database, base_path: &Utf8Path, options: CheckOptions) -> Result<()> {
+    let posts = database.get_checked_posts().await?;
+    let mut report = Report::default();
+    let mut names = HashSet::new();
+
+    for post in &posts {
+        let name = file_name(post.id, post.external_id, &post.extension);
+        let path = base_path.join(&name);
+
+        if !path.is_file() {
+            warn!("{name}: file is missing");
+            report.missing_files += 1;
+            if options.repair {
+                database.flag_redownload(post.id, "missing file").await?;
+            }
+            names.insert(name);
+            continue;
+        }
+
+        let mime = file_type(path.as_std_path())
+            .await
+            .map(|file_type| file_type.mime_type().to_string());
+        if mime.as_deref().ok() != Some(post.mime.as_str()) {
+            match &mime {
+                Ok(mime) => warn!("{name}: file is {mime} instead of {}", post.mime),
+                Err(err) => warn!("{name}: {err:#}"),
+            }
+            report.mismatched_types += 1;
+            if options.repair {
+                database.flag_redownload(post.id, "corrupt file").await?;
+            }
+        }
+
+        let thumb = base_path.join(".thumbs").join(format!("{name}.jpeg"));
+        if post.mime.starts_with("video/") && !thumb.is_file() {
+            warn!("{name}: video thumbnail is missing");
+            report.missing_thumbs += 1;
+            if options.repair {
+                match regenerate_thumbnail(&path, &thumb).await {
+                    Ok(()) => info!("{name}: regenerated video thumbnail"),
+                    Err(err) => warn!("{name}: could not regenerate thumbnail: {err:#}"),
+                }
+            }
+        }
+
+        names.insert(name);
+    }
+
+    for path in list_files(base_path).await? {
+        if !names.contains(path.as_str()) {
+            warn!("{path}: not part of any post");
+            report.orphaned_files += 1;
+            if options.repair {
+                quarantine(base_path, &path).await?;
+            }
+        }
+    }
+
+    for path in list_files(&base_path.join(".thumbs")).await? {
+        let belongs_to_post = path
+            .as_str()
+            .strip_suffix(".jpeg")
+            .is_some_and(|name| names.contains(name));
+        if !belongs_to_post {
+            warn!(".thumbs/{path}: not part of any post");
+            report.orphaned_thumbs += 1;
+            if options.repair {
+                quarantine(base_path, &Utf8Path::new(".thumbs").join(&path)).await?;
+            }
+        }
+    }
+
+    for path in list_files(&base_path.join(".minis")).await? {
+        let belongs_to_post = path
+            .as_str()
+            .strip_prefix("mini_")
+            .and_then(|name| name.strip_suffix(".jpeg"))
+            .is_some_and(|name| names.contains(name));
+        if !belongs_to_post {
+            warn!(".minis/{path}: not part of any post");
+            report.stray_minis += 1;
+            if options.repair {
+                quarantine(base_path, &Utf8Path::new(".minis").join(&path)).await?;
+            }
+        }
+    }
+
+    info!("Checked {} posts", posts.len());
+    info!("{} posts are missing their file", report.missing_files);
+    info!(
+        "{} files don't match the type of their post",
+        report.mismatched_types
+    );
+    info!(
+        "{} videos are missing their thumbnail",
+        report.missing_thumbs
+    );
+    info!("{} files don't belong to any post", report.orphaned_files);
+    info!(
+        "{} thumbnails don't belong to any post",
+        report.orphaned_thumbs
+    );
+    info!(
+        "{} mini thumbnails don't belong to any post",
+        report.stray_minis
+    );
+    if options.repair {
+        info!(
+            "Orphaned files were moved to {}",
+            base_path.join(QUARANTINE_DIR)
+        );
+    }
+    Ok(())
+}
+
+async fn regenerate_thumbnail(video: &Utf8Path, thumb: &Utf8Path) -> Result<()> {
+    let duration = probe_media(video.as_std_path())
+        .await?
+        .duration
+        .ok_or(anyhow!("ffprobe did not return video duration"))?;
+    video_thumbnail(video.as_std_path(), thumb.as_std_path(), duration).await
+}
+
+/// Returns the names of the files in `directory`, skipping hidden files like the database.
+async fn list_files(directory: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+    let mut files = Vec::new();
+    let mut entries = match tokio::fs::read_dir(directory).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(files),
+        Err(err) => return Err(err).context(format!("failed to read {directory}")),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else {
+            warn!(
+                "{directory}: skipping file with non UTF-8 name {:?}",
+                entry.file_name()
+            );
+            continue;
+        };
+        if !name.starts_with('.') && entry.file_type().await?.is_file() {
+            files.push(Utf8PathBuf::from(name));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Moves a file, given relative to the library, into the quarantine folder so it can be inspected
+/// before deleting it for good.
+async fn quarantine(base_path: &Utf8Path, path: &Utf8Path) -> Result<()> {
+    let target = base_path.join(QUARANTINE_DIR).join(path);
+    if let Some(parent) = target.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::rename(base_path.join(path), &target)
+        .await
+        .with_context(|| format!("failed to move {path} to {target}"))
+}
+
+impl Database {
+    async fn get_checked_posts(&self) -> Result<Vec<CheckedPost>> {
+        Ok(sqlx::query_as!(
+            CheckedPost,
+            "SELECT id, external_id, extension, mime FROM posts ORDER BY id"
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+}
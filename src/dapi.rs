@@ -0,0 +1,390 @@
+//! Client for the post API of Gelbooru 0.2 style sites like rule34.xxx
+//! (`index.php?page=dapi&s=post&q=index`), which exposes metadata the post pages don't show.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::fetcher::{curl, decode_html};
+
+/// Most posts the API returns per page.
+pub const MAX_LIMIT: u32 = 1000;
+/// The API refuses to page deeper than this.
+const MAX_PAGES: u32 = 200;
+
+#[derive(Clone)]
+pub struct DapiClient {
+    base_url: String,
+    /// `user_id` and `api_key`, which some sites require for API access.
+    credentials: Option<(String, String)>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DapiPost {
+    pub id: i64,
+    pub md5: Option<String>,
+    pub file_url: Option<String>,
+    /// `safe`, `questionable`, `explicit`, or the single letter some sites use instead.
+    pub rating: Option<String>,
+    pub score: Option<i64>,
+    pub source: Option<String>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub parent_id: Option<i64>,
+    /// As formatted by the site, e.g. `Sat Oct 18 10:00:00 +0000 2026`.
+    pub created_at: Option<String>,
+    pub uploader: Option<String>,
+    /// The API doesn't say which kind a tag is.
+    pub tags: Vec<String>,
+}
+
+impl DapiClient {
+    pub fn new(base_url: &str, credentials: Option<(String, String)>) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            credentials,
+        }
+    }
+
+    pub async fn post(&self, id: i64) -> Result<Option<DapiPost>> {
+        let mut posts = self.request(&format!("id={id}")).await?;
+        Ok(posts.pop())
+    }
+
+    /// A page of posts matching `tags`, `page` starts at 0.
+    pub async fn posts(&self, tags: &str, page: u32, limit: u32) -> Result<Vec<DapiPost>> {
+        self.request(&format!(
+            "tags={}&pid={page}&limit={}",
+            encode_query(tags),
+            limit.min(MAX_LIMIT)
+        ))
+        .await
+    }
+
+    /// Every post matching `tags`, as far as the API allows paging.
+    pub async fn all_posts(&self, tags: &str) -> Result<Vec<DapiPost>> {
+        let mut posts = Vec::new();
+        for page in 0..MAX_PAGES {
+            let page_posts = self.posts(tags, page, MAX_LIMIT).await?;
+            let last_page = page_posts.len() < MAX_LIMIT as usize;
+            posts.extend(page_posts);
+            if last_page {
+                break;
+            }
+        }
+        Ok(posts)
+    }
+
+    async fn request(&self, query: &str) -> Result<Vec<DapiPost>> {
+        let mut url = format!(
+            "{}/index.php?page=dapi&s=post&q=index&json=1&{query}",
+            self.base_url
+        );
+        if let Some((user_id, api_key)) = &self.credentials {
+            url.push_str(&format!(
+                "&user_id={}&api_key={}",
+                encode_query(user_id),
+                encode_query(api_key)
+            ));
+        }
+        let body = curl(&url, None).await?;
+        parse_posts(&String::from_utf8_lossy(&body))
+    }
+}
+
+/// Parses a response in either format, sites ignore `json=1` if they don't support it.
+pub fn parse_posts(body: &str) -> Result<Vec<DapiPost>> {
+    let body = body.trim();
+    if body.is_empty() {
+        return Ok(Vec::new());
+    }
+    if body.starts_with('<') {
+        parse_xml(body)
+    } else {
+        parse_json(body)
+    }
+}
+
+/// Accepts a plain array of posts or the `{"@attributes": ..., "post": [...]}` object newer
+/// Gelbooru versions return.
+pub fn parse_json(body: &str) -> Result<Vec<DapiPost>> {
+    let value: Value = serde_json::from_str(body).context("API returned invalid json")?;
+    let posts = match value {
+        Value::Array(posts) => posts,
+        Value::Object(mut object) => match object.remove("post") {
+            Some(Value::Array(posts)) => posts,
+            Some(post @ Value::Object(_)) => vec![post],
+            _ => Vec::new(),
+        },
+        _ => bail!("API returned unexpected json"),
+    };
+
+    posts
+        .into_iter()
+        .map(|post| {
+            let Value::Object(fields) = post else {
+                bail!("API returned a post that is not an object");
+            };
+            DapiPost::from_fields(|name| match fields.get(name)? {
+                Value::String(value) => Some(value.clone()),
+                Value::Null => None,
+                value => Some(value.to_string()),
+            })
+        })
+        .collect()
+}
+
+/// Every post is an element whose attributes hold the fields:
+/// `<posts count="1" offset="0"><post id="1" md5="..." tags=" a b " ... /></posts>`.
+pub fn parse_xml(body: &str) -> Result<Vec<DapiPost>> {
+    if body.contains("<error") || body.contains("success=\"false\"") {
+        bail!("API returned an error: {body}");
+    }
+
+    let mut posts = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find("<post ") {
+        rest = &rest[start + "<post ".len()..];
+        let end = rest
+            .find('>')
+            .ok_or(anyhow!("API returned unterminated xml"))?;
+        let fields = parse_attributes(&rest[..end]);
+        posts.push(DapiPost::from_fields(|name| fields.get(name).cloned())?);
+        rest = &rest[end..];
+    }
+    Ok(posts)
+}
+
+fn parse_attributes(element: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = element;
+    while let Some((name, value)) = rest.split_once("=\"") {
+        let Some((value, remaining)) = value.split_once('"') else {
+            break;
+        };
+        attributes.insert(name.trim().to_string(), decode_html(value));
+        rest = remaining;
+    }
+    attributes
+}
+
+impl DapiPost {
+    /// Builds a post from its fields, which have different names depending on the site.
+    fn from_fields(field: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let text = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| field(name))
+                .filter(|value| !value.is_empty())
+        };
+        let number = |names: &[&str]| text(names).and_then(|value| value.parse::<i64>().ok());
+        // Sites write 0 for dimensions they don't know and posts without a parent.
+        let nonzero = |names: &[&str]| number(names).filter(|value| *value != 0);
+
+        Ok(Self {
+            id: text(&["id"])
+                .and_then(|id| id.parse().ok())
+                .ok_or(anyhow!("API returned a post without id"))?,
+            md5: text(&["md5", "hash"]),
+            file_url: text(&["file_url"]),
+            rating: text(&["rating"]),
+            score: number(&["score"]),
+            source: text(&["source"]),
+            width: nonzero(&["width"]),
+            height: nonzero(&["height"]),
+            parent_id: nonzero(&["parent_id"]),
+            created_at: text(&["created_at"]),
+            uploader: text(&["owner", "creator_id", "creator"]),
+            tags: text(&["tags"])
+                .map(|tags| tags.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+fn encode_query(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b' ' => encoded.push('+'),
+            byte => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Newer Gelbooru versions wrap the posts in an object.
+    const GELBOORU_JSON: &str = r#"{
+        "@attributes": {"limit": 100, "offset": 0, "count": 2},
+        "post": [
+            {
+                "id": 9000001,
+                "created_at": "Sat Oct 18 10:00:00 -0500 2026",
+                "score": 0,
+                "width": 1200,
+                "height": 1600,
+                "md5": "0123456789abcdef0123456789abcdef",
+                "rating": "general",
+                "source": "",
+                "owner": "uploader",
+                "creator_id": 42,
+                "parent_id": 0,
+                "tags": "1girl cat_ears smile",
+                "file_url": "https://img3.gelbooru.com/images/01/23/0123.jpg"
+            },
+            {
+                "id": 9000002,
+                "score": 15,
+                "width": 0,
+                "height": 0,
+                "md5": "fedcba9876543210fedcba9876543210",
+                "rating": "explicit",
+                "parent_id": 9000001,
+                "tags": "",
+                "file_url": null
+            }
+        ]
+    }"#;
+
+    /// A single post isn't wrapped in an array.
+    const GELBOORU_SINGLE_JSON: &str = r#"{
+        "@attributes": {"limit": 100, "offset": 0, "count": 1},
+        "post": {"id": 5, "md5": "00000000000000000000000000000005", "tags": "cat"}
+    }"#;
+
+    /// rule34.xxx returns a plain array and calls the md5 `hash`.
+    const RULE34_JSON: &str = r#"[
+        {
+            "file_url": "https://api-cdn.rule34.xxx/images/1/abc.png",
+            "hash": "abcdefabcdefabcdefabcdefabcdefab",
+            "width": 800,
+            "height": 600,
+            "id": 12,
+            "owner": "someone",
+            "parent_id": 0,
+            "rating": "questionable",
+            "score": 7,
+            "tags": " tag_a  tag_b ",
+            "source": "https://example.com/post/1"
+        }
+    ]"#;
+
+    const SAFEBOORU_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<posts count="2" offset="0"><post height="600" score="3" file_url="https://safebooru.org/images/1/a.png" parent_id="" rating="s" tags=" cat &amp;_dog tom_&amp;_jerry " id="7" width="800" md5="77777777777777777777777777777777" creator_id="9" created_at="Sat Oct 18 10:00:00 +0000 2026" source="https://example.com/?a=1&amp;b=2" has_notes="false"/><post height="0" score="0" file_url="https://safebooru.org/images/1/b.png" parent_id="7" rating="q" tags="dog" id="8" width="0" md5="88888888888888888888888888888888" creator_id="9" source=""/></posts>"#;
+
+    #[test]
+    fn parses_wrapped_json() {
+        let posts = parse_posts(GELBOORU_JSON).unwrap();
+        assert_eq!(posts.len(), 2);
+
+        let post = &posts[0];
+        assert_eq!(post.id, 9000001);
+        assert_eq!(
+            post.md5.as_deref(),
+            Some("0123456789abcdef0123456789abcdef")
+        );
+        assert_eq!(
+            post.file_url.as_deref(),
+            Some("https://img3.gelbooru.com/images/01/23/0123.jpg")
+        );
+        assert_eq!(post.rating.as_deref(), Some("general"));
+        assert_eq!(post.score, Some(0));
+        assert_eq!(post.source, None);
+        assert_eq!((post.width, post.height), (Some(1200), Some(1600)));
+        assert_eq!(post.parent_id, None);
+        assert_eq!(post.uploader.as_deref(), Some("uploader"));
+        assert_eq!(post.tags, ["1girl", "cat_ears", "smile"]);
+
+        let post = &posts[1];
+        assert_eq!(post.score, Some(15));
+        assert_eq!((post.width, post.height), (None, None));
+        assert_eq!(post.parent_id, Some(9000001));
+        assert_eq!(post.file_url, None);
+        assert!(post.tags.is_empty());
+    }
+
+    #[test]
+    fn parses_single_json_post() {
+        let posts = parse_posts(GELBOORU_SINGLE_JSON).unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].id, 5);
+        assert_eq!(posts[0].tags, ["cat"]);
+    }
+
+    #[test]
+    fn parses_json_array() {
+        let posts = parse_posts(RULE34_JSON).unwrap();
+        assert_eq!(posts.len(), 1);
+
+        let post = &posts[0];
+        assert_eq!(post.id, 12);
+        assert_eq!(
+            post.md5.as_deref(),
+            Some("abcdefabcdefabcdefabcdefabcdefab")
+        );
+        assert_eq!(post.rating.as_deref(), Some("questionable"));
+        assert_eq!(post.score, Some(7));
+        assert_eq!(post.source.as_deref(), Some("https://example.com/post/1"));
+        assert_eq!(post.tags, ["tag_a", "tag_b"]);
+    }
+
+    #[test]
+    fn parses_xml_with_escaped_attributes() {
+        let posts = parse_posts(SAFEBOORU_XML).unwrap();
+        assert_eq!(posts.len(), 2);
+
+        let post = &posts[0];
+        assert_eq!(post.id, 7);
+        assert_eq!(post.tags, ["cat", "&_dog", "tom_&_jerry"]);
+        assert_eq!(post.source.as_deref(), Some("https://example.com/?a=1&b=2"));
+        assert_eq!(post.rating.as_deref(), Some("s"));
+        assert_eq!(post.parent_id, None);
+        assert_eq!(post.uploader.as_deref(), Some("9"));
+        assert_eq!(
+            post.created_at.as_deref(),
+            Some("Sat Oct 18 10:00:00 +0000 2026")
+        );
+
+        let post = &posts[1];
+        assert_eq!(post.id, 8);
+        assert_eq!(post.parent_id, Some(7));
+        assert_eq!(post.score, Some(0));
+        assert_eq!((post.width, post.height), (None, None));
+        assert_eq!(post.source, None);
+    }
+
+    #[test]
+    fn parses_empty_responses() {
+        assert!(parse_posts("").unwrap().is_empty());
+        assert!(parse_posts("[]").unwrap().is_empty());
+        assert!(
+            parse_posts(r#"{"@attributes": {"count": 0}}"#)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            parse_posts(r#"<?xml version="1.0"?><posts count="0" offset="0"></posts>"#)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn rejects_errors_and_posts_without_id() {
+        assert!(
+            parse_posts(r#"<response success="false" reason="Missing authentication"/>"#).is_err()
+        );
+        assert!(parse_posts(r#"[{"md5": "abc"}]"#).is_err());
+        assert!(parse_posts("not json").is_err());
+    }
+}
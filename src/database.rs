@@ -1,10 +1,22 @@
 use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::{Result, bail};
 use camino::Utf8Path;
-use sqlx::{SqlitePool, sqlite::SqliteConnectOptions};
+use sqlx::{Connection, SqlitePool, sqlite::SqliteConnectOptions};
 
-const MIGRATIONS: &[&str] = &[include_str!("./migrations/202508291609-init.sql")];
+const MIGRATIONS: &[&str] = &[
+    include_str!("./migrations/202508291609-init.sql"),
+    include_str!("./migrations/202610181000-tag-history.sql"),
+    include_str!("./migrations/202610181100-content-hashes.sql"),
+    include_str!("./migrations/202610181200-perceptual-hash.sql"),
+    include_str!("./migrations/202610181300-media-info.sql"),
+    include_str!("./migrations/202610181400-redownload.sql"),
+    include_str!("./migrations/202610181500-reconcile.sql"),
+    include_str!("./migrations/202610181600-sync-sessions.sql"),
+    include_str!("./migrations/202610181700-jobs.sql"),
+    include_str!("./migrations/202610181800-site-metadata.sql"),
+    include_str!("./migrations/202610181900-sites.sql"),
+];
 
 #[derive(Clone)]
 pub struct Database {
@@ -26,16 +38,33 @@ impl Database {
     }
 
     async fn run_migrations(&self) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
         let current_version: i64 = sqlx::query_scalar("PRAGMA user_version")
-            .fetch_one(&self.pool)
+            .fetch_one(&mut *connection)
+            .await?;
+
+        // Migrations that rebuild a table have to drop the old one, which foreign keys don't allow.
+        // They can't be turned off inside a transaction, so they are checked by hand instead.
+        sqlx::query("PRAGMA foreign_keys = OFF")
+            .execute(&mut *connection)
             .await?;
 
         for (index, migration) in MIGRATIONS.iter().enumerate().skip(current_version as usize) {
             let version = index + 1;
-            let mut transaction = self.pool.begin().await?;
+            let mut transaction = connection.begin().await?;
 
             sqlx::query(migration).execute(&mut *transaction).await?;
 
+            let violations = sqlx::query("PRAGMA foreign_key_check")
+                .fetch_all(&mut *transaction)
+                .await?;
+            if !violations.is_empty() {
+                bail!(
+                    "migration {version} broke {} foreign keys",
+                    violations.len()
+                );
+            }
+
             sqlx::query(format!("PRAGMA user_version = {version}").as_str())
                 .execute(&mut *transaction)
                 .await?;
@@ -43,6 +72,10 @@ impl Database {
             transaction.commit().await?;
         }
 
+        sqlx::query("PRAGMA foreign_keys = ON")
+            .execute(&mut *connection)
+            .await?;
+
         Ok(())
     }
 }
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    database::Database,
+    json_ok,
+    server::{AppResult, AppState, BadRequest, NotFound},
+    site::{Site, SiteQuery},
+};
+
+const DEFAULT_DISTANCE: u32 = 8;
+const MAX_DISTANCE: u32 = 32;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateGroup {
+    sha256: String,
+    post_ids: Vec<i64>,
+}
+
+pub async fn get_duplicates(
+    State(AppState { database, .. }): State<AppState>,
+    Query(SiteQuery { site }): Query<SiteQuery>,
+) -> AppResult<Json<Value>> {
+    json_ok!({"duplicates": database.get_duplicates(site).await?})
+}
+
+#[derive(Deserialize)]
+pub struct SimilarQuery {
+    /// Maximum number of differing bits between two perceptual hashes.
+    distance: Option<u32>,
+    #[serde(default)]
+    site: Site,
+}
+
+#[derive(Serialize)]
+pub struct SimilarPost {
+    id: i64,
+    distance: u32,
+}
+
+pub async fn get_similar_posts(
+    State(AppState { database, .. }): State<AppState>,
+    Path(post_id): Path<i64>,
+    Query(SimilarQuery { distance, site }): Query<SimilarQuery>,
+) -> AppResult<Json<Value>> {
+    let max_distance = max_distance(distance)?;
+    let Some(phash) = database.get_phash(site, post_id).await? else {
+        return Err(NotFound(format!("post {post_id} has no perceptual hash")).into());
+    };
+
+    let mut similar: Vec<SimilarPost> = database
+        .get_phashes(site)
+        .await?
+        .into_iter()
+        .filter(|&(external_id, _)| external_id != post_id)
+        .map(|(external_id, other)| SimilarPost {
+            id: external_id,
+            distance: (phash ^ other).count_ones(),
+        })
+        .filter(|post| post.distance <= max_distance)
+        .collect();
+    similar.sort_by_key(|post| (post.distance, post.id));
+
+    json_ok!({"similar": similar})
+}
+
+/// Reports groups of posts that look alike. Posts end up in the same cluster if they are within
+/// the distance of any other post in the cluster.
+pub async fn get_similar_clusters(
+    State(AppState { database, .. }): State<AppState>,
+    Query(SimilarQuery { distance, site }): Query<SimilarQuery>,
+) -> AppResult<Json<Value>> {
+    let max_distance = max_distance(distance)?;
+    let phashes = database.get_phashes(site).await?;
+    let clusters = tokio::task::spawn_blocking(move || cluster(&phashes, max_distance)).await?;
+    json_ok!({"clusters": clusters})
+}
+
+fn max_distance(distance: Option<u32>) -> Result<u32, BadRequest> {
+    match distance {
+        None => Ok(DEFAULT_DISTANCE),
+        Some(distance) if distance <= MAX_DISTANCE => Ok(distance),
+        Some(_) => Err(BadRequest(format!(
+            "distance can be at most {MAX_DISTANCE}"
+        ))),
+    }
+}
+
+fn cluster(phashes: &[(i64, i64)], max_distance: u32) -> Vec<Vec<i64>> {
+    fn root(parents: &mut [usize], mut index: usize) -> usize {
+        while parents[index] != index {
+            parents[index] = parents[parents[index]];
+            index = parents[index];
+        }
+        index
+    }
+
+    let mut parents: Vec<usize> = (0..phashes.len()).collect();
+    for (a, &(_, hash_a)) in phashes.iter().enumerate() {
+        for (b, &(_, hash_b)) in phashes.iter().enumerate().skip(a + 1) {
+            if (hash_a ^ hash_b).count_ones() <= max_distance {
+                let (root_a, root_b) = (root(&mut parents, a), root(&mut parents, b));
+                parents[root_b] = root_a;
+            }
+        }
+    }
+
+    let mut clusters: HashMap<usize, Vec<i64>> = HashMap::new();
+    for (index, &(external_id, _)) in phashes.iter().enumerate() {
+        clusters
+            .entry(root(&mut parents, index))
+            .or_default()
+            .push(external_id);
+    }
+
+    let mut clusters: Vec<Vec<i64>> = clusters
+        .into_values()
+        .filter(|cluster| cluster.len() > 1)
+        .map(|mut cluster| {
+            cluster.sort();
+            cluster
+        })
+        .collect();
+    clusters.sort();
+    clusters
+}
+
+impl Database {
+    async fn get_phash(&self, site: Site, external_id: i64) -> Result<Option<i64>> {
+        let site = site.as_str();
+        Ok(sqlx::query_scalar!(
+            "SELECT phash FROM posts WHERE site = ? AND external_id = ?",
+            site,
+            external_id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .flatten())
+    }
+
+    /// Returns external id and perceptual hash of every post of the site that has one.
+    async fn get_phashes(&self, site: Site) -> Result<Vec<(i64, i64)>> {
+        let site = site.as_str();
+        Ok(sqlx::query!(
+            r#"SELECT external_id, phash AS "phash!"
+            FROM posts
+            WHERE site = ? AND phash IS NOT NULL"#,
+            site
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| (row.external_id, row.phash))
+        .collect())
+    }
+
+    /// Groups posts that were uploaded with identical content.
+    async fn get_duplicates(&self, site: Site) -> Result<Vec<DuplicateGroup>> {
+        let site = site.as_str();
+        let rows = sqlx::query!(
+            r#"SELECT sha256 AS "sha256!", external_id
+            FROM posts
+            WHERE site = ? AND sha256 IN (
+                SELECT sha256 FROM posts
+                WHERE site = ? AND sha256 IS NOT NULL
+                GROUP BY sha256
+                HAVING COUNT(1) > 1
+            )
+            ORDER BY sha256, external_id"#,
+            site,
+            site
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut groups: Vec<DuplicateGroup> = Vec::new();
+        for row in rows {
+            match groups.last_mut() {
+                Some(group) if group.sha256 == row.sha256 => group.post_ids.push(row.external_id),
+                _ => groups.push(DuplicateGroup {
+                    sha256: row.sha256,
+                    post_ids: vec![row.external_id],
+                }),
+            }
+        }
+
+        Ok(groups)
+    }
+}
@@ -0,0 +1,133 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tokio_stream::{
+    Stream, StreamExt,
+    wrappers::{BroadcastStream, errors::BroadcastStreamRecvError},
+};
+
+use crate::server::AppState;
+
+/// Events that are missed by a subscriber that can't keep up are dropped after this many.
+const CAPACITY: usize = 256;
+
+/// Progress of uploads and syncs, published to everyone listening on `/events`.
+#[derive(Clone, Serialize)]
+#[serde(
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase",
+    tag = "type"
+)]
+pub enum ProgressEvent {
+    UploadReceived {
+        job_id: i64,
+        post_id: i64,
+    },
+    ProcessingStarted {
+        job_id: i64,
+        post_id: i64,
+    },
+    Processed {
+        job_id: i64,
+        post_id: i64,
+        result: Value,
+    },
+    Failed {
+        job_id: i64,
+        post_id: i64,
+        error: String,
+    },
+    FetchFailed {
+        post_id: i64,
+        error: String,
+    },
+    FetchSearchFailed {
+        tags: String,
+        error: String,
+    },
+    SyncCheckpoint {
+        session_id: i64,
+        pid: i64,
+        downloaded: i64,
+    },
+    SyncError {
+        session_id: i64,
+        post_id: Option<i64>,
+        error: String,
+    },
+    SyncFinished {
+        session_id: i64,
+    },
+}
+
+impl ProgressEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::UploadReceived { .. } => "uploadReceived",
+            Self::ProcessingStarted { .. } => "processingStarted",
+            Self::Processed { .. } => "processed",
+            Self::Failed { .. } => "failed",
+            Self::FetchFailed { .. } => "fetchFailed",
+            Self::FetchSearchFailed { .. } => "fetchSearchFailed",
+            Self::SyncCheckpoint { .. } => "syncCheckpoint",
+            Self::SyncError { .. } => "syncError",
+            Self::SyncFinished { .. } => "syncFinished",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<ProgressEvent>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+
+    pub fn publish(&self, event: ProgressEvent) {
+        // Fails only if nobody is listening, which is fine.
+        _ = self.sender.send(event);
+    }
+}
+
+/// Server-Sent Events stream of [`ProgressEvent`]s. Every event is named after its type and
+/// carries its fields as JSON. Subscribers that fall behind get a `lagged` event with the number of
+/// events they missed.
+pub async fn stream_events(
+    State(AppState {
+        events,
+        shutdown_token,
+        ..
+    }): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // The stream has to end on shutdown, the server waits for open connections to close.
+    let shutdown = tokio_stream::once(())
+        .then(move |()| shutdown_token.clone().cancelled_owned())
+        .map(|()| None);
+    let stream = BroadcastStream::new(events.sender.subscribe())
+        .map(Some)
+        .merge(shutdown)
+        .map_while(|event| event)
+        .map(|event| {
+            Ok(match event {
+                Ok(event) => Event::default()
+                    .event(event.name())
+                    .json_data(&event)
+                    .unwrap_or_else(|_| Event::default().event(event.name())),
+                Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                    Event::default().event("lagged").data(missed.to_string())
+                }
+            })
+        });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
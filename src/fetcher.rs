@@ -0,0 +1,340 @@
+//! Downloads posts on the server instead of in the browser, so syncing doesn't depend on a tab that
+//! stays open. Requests are made with `curl`, the same way media is processed with ffmpeg.
+//!
+//! The original file is found through the API when possible. The post page is still needed for the
+//! tags, since only it tells their kinds apart.
+
+use std::{collections::HashSet, process::Stdio, time::Duration};
+
+use anyhow::{Context, Result, anyhow, bail};
+use axum::{
+    Json,
+    extract::{Path, State},
+};
+use camino::Utf8Path;
+use serde::Deserialize;
+use serde_json::Value;
+use tempfile::NamedTempFile;
+use tokio::{io::AsyncWriteExt, process::Command};
+use tracing::{debug, info, warn};
+
+use crate::{
+    dapi::{DapiClient, DapiPost, MAX_LIMIT},
+    database::Database,
+    events::ProgressEvent,
+    json_ok,
+    server::{AppResult, AppState, BadRequest, NotFound, ServerOptions},
+    site::Site,
+    upload::{ContentHashes, PostData, Rating, SiteMetadata, Tag, TagKind},
+};
+
+/// Pause between posts to not hammer the site.
+const REQUEST_DELAY: Duration = Duration::from_millis(250);
+const TAG_LINK: &str = "page=post&amp;s=list&amp;tags=";
+
+#[derive(Clone)]
+pub struct Fetcher {
+    base_url: String,
+    /// Unknown hosts like mirrors are assumed to be rule34.xxx.
+    site: Site,
+    dapi: DapiClient,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FetchRequest {
+    #[serde(default)]
+    post_ids: Vec<i64>,
+    /// Also fetch every post matching this search, e.g. `fav:123` or `artist_name rating:safe`.
+    /// Unlike the given ids, posts that are already downloaded are skipped.
+    tags: Option<String>,
+}
+
+/// Downloads the given posts in the background and queues them like uploads. Progress and
+/// failures are published as events. Posts matching `tags` are searched for in the background too,
+/// so `fetching` only counts the given ids.
+pub async fn fetch_posts(
+    State(AppState {
+        database,
+        base_path,
+        options,
+        jobs,
+        events,
+        ..
+    }): State<AppState>,
+    Json(FetchRequest { mut post_ids, tags }): Json<FetchRequest>,
+) -> AppResult<Json<Value>> {
+    let fetcher = Fetcher::from_options(&options)?;
+    let count = post_ids.len();
+    let searching = tags.is_some();
+
+    tokio::spawn(async move {
+        if let Some(tags) = tags {
+            // Paging through a large search takes a while, so it is done here instead of before
+            // responding.
+            let found = match fetcher.dapi.all_posts(&tags).await {
+                Ok(posts) => {
+                    let ids = posts.into_iter().map(|post| post.id).collect();
+                    filter_new_posts(&database, fetcher.site, ids).await
+                }
+                Err(err) => Err(err),
+            };
+            match found {
+                Ok(found) => {
+                    let mut seen: HashSet<i64> = post_ids.iter().copied().collect();
+                    post_ids.extend(found.into_iter().filter(|id| seen.insert(*id)));
+                }
+                Err(err) => {
+                    warn!("Failed to search for '{tags}': {err:#}");
+                    events.publish(ProgressEvent::FetchSearchFailed {
+                        tags,
+                        error: format!("{err:#}"),
+                    });
+                }
+            }
+        }
+
+        for post_id in post_ids {
+            let queued = match fetcher.fetch_post(post_id).await {
+                Ok(data) => jobs.enqueue(&database, &base_path, data).await,
+                Err(err) => Err(err),
+            };
+            match queued {
+                Ok(job_id) => events.publish(ProgressEvent::UploadReceived { job_id, post_id }),
+                Err(err) => {
+                    warn!("Failed to fetch post {post_id}: {err:#}");
+                    events.publish(ProgressEvent::FetchFailed {
+                        post_id,
+                        error: format!("{err:#}"),
+                    });
+                }
+            }
+            tokio::time::sleep(REQUEST_DELAY).await;
+        }
+    });
+
+    json_ok!({ "ok": true, "fetching": count, "searching": searching })
+}
+
+/// Drops the posts that are already downloaded, in chunks so large searches stay below the limit of
+/// bound parameters.
+async fn filter_new_posts(database: &Database, site: Site, post_ids: Vec<i64>) -> Result<Vec<i64>> {
+    let mut downloaded = HashSet::new();
+    for chunk in post_ids.chunks(MAX_LIMIT as usize) {
+        downloaded.extend(
+            database
+                .filter_already_downloaded_posts(site, chunk)
+                .await?,
+        );
+    }
+    Ok(post_ids
+        .into_iter()
+        .filter(|id| !downloaded.contains(id))
+        .collect())
+}
+
+/// Returns what the API of the site knows about a post, without downloading it.
+pub async fn get_post_metadata(
+    State(AppState { options, .. }): State<AppState>,
+    Path(post_id): Path<i64>,
+) -> AppResult<Json<DapiPost>> {
+    let fetcher = Fetcher::from_options(&options)?;
+    let Some(post) = fetcher.dapi.post(post_id).await? else {
+        return Err(NotFound(format!("post {post_id} not found on the site")).into());
+    };
+    Ok(Json(post))
+}
+
+impl Fetcher {
+    pub fn new(base_url: &str, credentials: Option<(String, String)>) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            site: Site::from_url(base_url).unwrap_or_default(),
+            dapi: DapiClient::new(base_url, credentials),
+        }
+    }
+
+    fn from_options(options: &ServerOptions) -> AppResult<Self> {
+        let Some(base_url) = &options.fetch_base_url else {
+            return Err(BadRequest(
+                "the fetcher is disabled, start the server with --fetch-from".to_string(),
+            )
+            .into());
+        };
+        Ok(Self::new(base_url, options.api_credentials.clone()))
+    }
+
+    /// Downloads the post page and the original file of a post.
+    pub async fn fetch_post(&self, id: i64) -> Result<PostData> {
+        let page = curl(
+            &format!("{}/index.php?page=post&s=view&id={id}", self.base_url),
+            None,
+        )
+        .await?;
+        let page = String::from_utf8_lossy(&page);
+        let tags = parse_tags(&page);
+        let post = self.dapi.post(id).await.unwrap_or_else(|err| {
+            debug!("Falling back to the post page for post {id}: {err:#}");
+            None
+        });
+        let image_url = match post.as_ref().and_then(|post| post.file_url.as_deref()) {
+            Some(file_url) => self.resolve(file_url),
+            None => self.resolve(&parse_image_url(&page)?),
+        };
+
+        let image = NamedTempFile::new().context("Failed to create temp file for image")?;
+        curl(&image_url, Some(image.path())).await?;
+        let path = Utf8Path::from_path(image.path()).ok_or(anyhow!("temp path is not UTF-8"))?;
+        let hashes = ContentHashes::of_file(path).await?;
+        info!("Fetched post {id} from {image_url}");
+
+        let data = PostData {
+            site: self.site,
+            id,
+            image,
+            hashes,
+            tags: Some(tags),
+            replace: false,
+            metadata: post.map(SiteMetadata::from).unwrap_or_default(),
+        };
+        data.verify_md5()?;
+        Ok(data)
+    }
+
+    fn resolve(&self, url: &str) -> String {
+        if url.starts_with("//") {
+            format!("https:{url}")
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            url.to_string()
+        } else {
+            format!("{}/{}", self.base_url, url.trim_start_matches('/'))
+        }
+    }
+}
+
+impl From<DapiPost> for SiteMetadata {
+    fn from(post: DapiPost) -> Self {
+        Self {
+            rating: post.rating.as_deref().and_then(Rating::parse),
+            score: post.score,
+            source: post.source,
+            parent_id: post.parent_id,
+            md5: post.md5.map(|md5| md5.to_ascii_lowercase()),
+        }
+    }
+}
+
+/// Returns the response body, or writes it to `output` if given. The url is passed in a config on
+/// stdin, so API keys in it don't show up in the process list.
+pub async fn curl(url: &str, output: Option<&std::path::Path>) -> Result<Vec<u8>> {
+    let mut command = Command::new("curl");
+    command
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--fail")
+        .arg("--location")
+        .arg("--retry")
+        .arg("3")
+        .arg("--user-agent")
+        .arg(concat!("arueshalae/", env!("CARGO_PKG_VERSION")));
+    if let Some(output) = output {
+        command.arg("--output").arg(output);
+    }
+    let mut child = command
+        .arg("--config")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("failed to start curl. is it installed?")?;
+
+    let config = format!(
+        "url = \"{}\"\n",
+        url.replace('\\', "\\\\").replace('"', "\\\"")
+    );
+    let mut stdin = child.stdin.take().ok_or(anyhow!("curl has no stdin"))?;
+    stdin.write_all(config.as_bytes()).await?;
+    drop(stdin);
+    let result = child.wait_with_output().await?;
+
+    if !result.status.success() {
+        bail!(
+            "failed to download {}: {}",
+            redact_url(url),
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+    Ok(result.stdout)
+}
+
+/// Hides the API key in urls that end up in logs, events and responses.
+fn redact_url(url: &str) -> String {
+    let Some(start) = url.find("api_key=").map(|start| start + "api_key=".len()) else {
+        return url.to_string();
+    };
+    let end = url[start..].find('&').map_or(url.len(), |end| start + end);
+    format!("{}***{}", &url[..start], &url[end..])
+}
+
+/// Reads the tags from the sidebar of a post page, which lists them as
+/// `<li class="tag-type-general tag">...<a href="index.php?page=post&amp;s=list&amp;tags=name">name</a>`.
+fn parse_tags(page: &str) -> Vec<Tag> {
+    let mut tags: Vec<Tag> = Vec::new();
+    let mut rest = page;
+
+    while let Some(start) = rest.find("<li class=\"") {
+        rest = &rest[start + "<li class=\"".len()..];
+        let item = &rest[..rest.find("</li>").unwrap_or(rest.len())];
+        let Some((class, body)) = item.split_once('"') else {
+            continue;
+        };
+
+        let mut classes = class.split_whitespace();
+        if !classes.clone().any(|class| class == "tag") {
+            continue;
+        }
+        let Some(kind) = classes
+            .find_map(|class| class.strip_prefix("tag-type-"))
+            .and_then(TagKind::parse)
+        else {
+            continue;
+        };
+
+        let Some(name) = body
+            .split_once(TAG_LINK)
+            .and_then(|(_, link)| link.split_once('>'))
+            .and_then(|(_, text)| text.split_once("</a>"))
+            .map(|(name, _)| decode_html(name.trim()).replace(' ', "_"))
+        else {
+            continue;
+        };
+
+        if !name.is_empty() && !tags.iter().any(|tag| tag.name == name) {
+            tags.push(Tag { name, kind });
+        }
+    }
+    tags
+}
+
+/// Finds the link labeled "Original image" in the options of a post page.
+fn parse_image_url(page: &str) -> Result<String> {
+    let label = page
+        .find(">Original image<")
+        .ok_or(anyhow!("could not find original image link"))?;
+    let href = page[..label]
+        .rfind("href=\"")
+        .ok_or(anyhow!("could not find original image link"))?;
+    let url = &page[href + "href=\"".len()..label];
+    let url = url.split('"').next().unwrap_or_default();
+    Ok(decode_html(url))
+}
+
+pub fn decode_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#039;", "'")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
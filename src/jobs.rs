@@ -0,0 +1,435 @@
+//! Uploads are processed by background workers, so converting a large video doesn't keep the
+//! upload request open. The uploaded file is kept in `.jobs` and the job in the database, which
+//! lets queued jobs survive a restart.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result, anyhow};
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tempfile::{NamedTempFile, TempPath};
+use tokio::{sync::Notify, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    database::Database,
+    events::{Events, ProgressEvent},
+    json_ok,
+    media_processor::move_file,
+    server::{AppResult, AppState, NotFound, ServerOptions},
+    site::Site,
+    upload::{ContentHashes, PostData, store_post},
+};
+
+const JOBS_DIR: &str = ".jobs";
+const LIST_LIMIT: i64 = 100;
+
+/// Wakes up idle workers when a job is queued.
+#[derive(Clone, Default)]
+pub struct JobQueue {
+    notify: Arc<Notify>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    id: i64,
+    site: String,
+    #[serde(rename = "postId")]
+    external_id: i64,
+    /// `pending` until the upload is stored, then `queued`, `processing`, `done` or `failed`.
+    state: String,
+    /// Response of the upload once the job is done.
+    result: Option<Value>,
+    error: Option<String>,
+    created_at: NaiveDateTime,
+    started_at: Option<NaiveDateTime>,
+    finished_at: Option<NaiveDateTime>,
+}
+
+struct JobRow {
+    id: i64,
+    site: String,
+    external_id: i64,
+    state: String,
+    result: Option<String>,
+    error: Option<String>,
+    created_at: NaiveDateTime,
+    started_at: Option<NaiveDateTime>,
+    finished_at: Option<NaiveDateTime>,
+}
+
+impl From<JobRow> for Job {
+    fn from(row: JobRow) -> Self {
+        Self {
+            id: row.id,
+            site: row.site,
+            external_id: row.external_id,
+            state: row.state,
+            result: row
+                .result
+                .and_then(|result| serde_json::from_str(&result).ok()),
+            error: row.error,
+            created_at: row.created_at,
+            started_at: row.started_at,
+            finished_at: row.finished_at,
+        }
+    }
+}
+
+struct ClaimedJob {
+    id: i64,
+    site: String,
+    external_id: i64,
+    sha256: String,
+    md5: String,
+    tags: Option<String>,
+    replace: bool,
+    metadata: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct JobsQuery {
+    state: Option<String>,
+}
+
+pub async fn get_job(
+    State(AppState { database, .. }): State<AppState>,
+    Path(job_id): Path<i64>,
+) -> AppResult<Json<Job>> {
+    let Some(job) = database.get_job(job_id).await? else {
+        return Err(NotFound(format!("job {job_id} not found")).into());
+    };
+    Ok(Json(job))
+}
+
+/// Lists the latest jobs, optionally only those in the given state.
+pub async fn list_jobs(
+    State(AppState { database, .. }): State<AppState>,
+    Query(JobsQuery { state }): Query<JobsQuery>,
+) -> AppResult<Json<Value>> {
+    json_ok!({ "jobs": database.get_jobs(state.as_deref()).await? })
+}
+
+impl JobQueue {
+    pub async fn enqueue(
+        &self,
+        database: &Database,
+        base_path: &Utf8Path,
+        data: PostData,
+    ) -> Result<i64> {
+        let tags = data.tags.as_ref().map(serde_json::to_string).transpose()?;
+        let metadata = serde_json::to_string(&data.metadata)?;
+        let id = database
+            .insert_job(
+                data.site,
+                data.id,
+                &data.hashes,
+                tags.as_deref(),
+                data.replace,
+                &metadata,
+            )
+            .await?;
+
+        // The job is inserted as pending so no worker claims it while the upload is still being
+        // moved, which is a copy when `.jobs` is on another filesystem.
+        let path = job_file(base_path, id);
+        if let Err(err) = move_file(data.image.path(), &path).await {
+            database
+                .finish_job(id, None, Some(&format!("failed to store upload: {err:#}")))
+                .await?;
+            return Err(err.context(format!("failed to store upload in {path}")));
+        }
+        database.queue_job(id).await?;
+        // The database decides which job runs next, the permit only has to wake a worker.
+        self.notify.notify_one();
+        Ok(id)
+    }
+}
+
+/// Starts `workers` tasks that process queued jobs until shutdown. Jobs that were processing when
+/// the server stopped are queued again, uploads that weren't stored yet fail.
+pub async fn spawn_workers(
+    database: &Database,
+    base_path: &Utf8Path,
+    options: &ServerOptions,
+    queue: &JobQueue,
+    events: &Events,
+    workers: usize,
+    shutdown_token: &CancellationToken,
+) -> Result<Vec<JoinHandle<()>>> {
+    tokio::fs::create_dir_all(base_path.join(JOBS_DIR)).await?;
+    for id in database.fail_pending_jobs().await? {
+        // The upload may have been partially copied, there is nothing to process.
+        let path = job_file(base_path, id);
+        match tokio::fs::remove_file(&path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                warn!("Failed to remove {path}: {err}");
+            }
+            _ => {}
+        }
+    }
+    let requeued = database.requeue_interrupted_jobs().await?;
+    if requeued > 0 {
+        info!("Queued {requeued} interrupted uploads again");
+    }
+
+    Ok((0..workers.max(1))
+        .map(|_| {
+            let database = database.clone();
+            let base_path = base_path.to_path_buf();
+            let options = options.clone();
+            let queue = queue.clone();
+            let events = events.clone();
+            let shutdown_token = shutdown_token.clone();
+
+            tokio::spawn(async move {
+                loop {
+                    let idle = match database.claim_job().await {
+                        Ok(Some(job)) => {
+                            run_job(&database, &base_path, &options, &events, job).await;
+                            false
+                        }
+                        Ok(None) => true,
+                        Err(err) => {
+                            warn!("Failed to claim job: {err:#}");
+                            true
+                        }
+                    };
+
+                    if idle {
+                        tokio::select! {
+                            _ = shutdown_token.cancelled() => break,
+                            _ = queue.notify.notified() => {},
+                        }
+                    } else if shutdown_token.is_cancelled() {
+                        break;
+                    }
+                }
+            })
+        })
+        .collect())
+}
+
+async fn run_job(
+    database: &Database,
+    base_path: &Utf8Path,
+    options: &ServerOptions,
+    events: &Events,
+    job: ClaimedJob,
+) {
+    let (id, post_id) = (job.id, job.external_id);
+    events.publish(ProgressEvent::ProcessingStarted {
+        job_id: id,
+        post_id,
+    });
+    let result = match job_data(base_path, job) {
+        Ok(data) => store_post(database, base_path, options, data).await,
+        Err(err) => Err(err),
+    };
+
+    let finished = match result {
+        Ok(result) => {
+            let finished = database
+                .finish_job(id, Some(&result.to_string()), None)
+                .await;
+            events.publish(ProgressEvent::Processed {
+                job_id: id,
+                post_id,
+                result,
+            });
+            finished
+        }
+        Err(err) => {
+            warn!("Upload job {id} failed: {err:#}");
+            let error = format!("{err:#}");
+            let finished = database.finish_job(id, None, Some(&error)).await;
+            events.publish(ProgressEvent::Failed {
+                job_id: id,
+                post_id,
+                error,
+            });
+            finished
+        }
+    };
+    if let Err(err) = finished {
+        warn!("Failed to save the state of job {id}: {err:#}");
+    }
+}
+
+fn job_data(base_path: &Utf8Path, job: ClaimedJob) -> Result<PostData> {
+    let path = job_file(base_path, job.id);
+    let file = std::fs::File::open(&path).with_context(|| format!("upload {path} is missing"))?;
+    let tags = job
+        .tags
+        .map(|tags| serde_json::from_str(&tags))
+        .transpose()
+        .context("invalid tags in job")?;
+    // Jobs queued before the site metadata was stored don't have any.
+    let metadata = job
+        .metadata
+        .map(|metadata| serde_json::from_str(&metadata))
+        .transpose()
+        .context("invalid site metadata in job")?
+        .unwrap_or_default();
+
+    Ok(PostData {
+        site: Site::parse(&job.site).ok_or(anyhow!("unknown site '{}' in job", job.site))?,
+        id: job.external_id,
+        // Removes the upload once the job is done with it.
+        image: NamedTempFile::from_parts(file, TempPath::from_path(path)),
+        hashes: ContentHashes {
+            sha256: job.sha256,
+            md5: job.md5,
+        },
+        tags,
+        replace: job.replace,
+        metadata,
+    })
+}
+
+fn job_file(base_path: &Utf8Path, id: i64) -> Utf8PathBuf {
+    base_path.join(JOBS_DIR).join(id.to_string())
+}
+
+impl Database {
+    async fn insert_job(
+        &self,
+        site: Site,
+        external_id: i64,
+        hashes: &ContentHashes,
+        tags: Option<&str>,
+        replace: bool,
+        metadata: &str,
+    ) -> Result<i64> {
+        let site = site.as_str();
+        Ok(sqlx::query_scalar!(
+            r#"INSERT INTO jobs (site, external_id, sha256, md5, tags, replace, metadata, state)
+            VALUES (?, ?, ?, ?, ?, ?, ?, 'pending')
+            RETURNING id"#,
+            site,
+            external_id,
+            hashes.sha256,
+            hashes.md5,
+            tags,
+            replace,
+            metadata
+        )
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    /// Marks the oldest queued job as processing and returns it, the update makes sure no two
+    /// workers get the same job. Jobs for a post that is already being processed wait until it is
+    /// done, both would store the post otherwise.
+    async fn claim_job(&self) -> Result<Option<ClaimedJob>> {
+        Ok(sqlx::query_as!(
+            ClaimedJob,
+            r#"UPDATE jobs
+            SET state = 'processing', started_at = CURRENT_TIMESTAMP
+            WHERE id = (
+                SELECT j.id FROM jobs j
+                WHERE j.state = 'queued' AND NOT EXISTS (
+                    SELECT 1 FROM jobs p
+                    WHERE p.state = 'processing'
+                        AND p.site = j.site AND p.external_id = j.external_id
+                )
+                ORDER BY j.id LIMIT 1
+            )
+            RETURNING id AS "id!", site, external_id, sha256, md5, tags, replace, metadata"#
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn finish_job(&self, id: i64, result: Option<&str>, error: Option<&str>) -> Result<()> {
+        let state = if error.is_some() { "failed" } else { "done" };
+        sqlx::query!(
+            r#"UPDATE jobs
+            SET state = ?, result = ?, error = ?, finished_at = CURRENT_TIMESTAMP
+            WHERE id = ?"#,
+            state,
+            result,
+            error,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn queue_job(&self, id: i64) -> Result<()> {
+        sqlx::query!(
+            "UPDATE jobs SET state = 'queued' WHERE id = ? AND state = 'pending'",
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Fails the jobs whose upload wasn't stored when the server stopped and returns their ids.
+    async fn fail_pending_jobs(&self) -> Result<Vec<i64>> {
+        Ok(sqlx::query_scalar!(
+            r#"UPDATE jobs
+            SET state = 'failed', error = 'the server stopped before the upload was stored',
+                finished_at = CURRENT_TIMESTAMP
+            WHERE state = 'pending'
+            RETURNING id AS "id!""#
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn requeue_interrupted_jobs(&self) -> Result<u64> {
+        Ok(sqlx::query!(
+            "UPDATE jobs SET state = 'queued', started_at = NULL WHERE state = 'processing'"
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected())
+    }
+
+    async fn get_job(&self, id: i64) -> Result<Option<Job>> {
+        Ok(sqlx::query_as!(
+            JobRow,
+            r#"SELECT id, site, external_id, state, result, error, created_at,
+                started_at AS "started_at: NaiveDateTime",
+                finished_at AS "finished_at: NaiveDateTime"
+            FROM jobs
+            WHERE id = ?"#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .map(Job::from))
+    }
+
+    async fn get_jobs(&self, state: Option<&str>) -> Result<Vec<Job>> {
+        Ok(sqlx::query_as!(
+            JobRow,
+            r#"SELECT id, site, external_id, state, result, error, created_at,
+                started_at AS "started_at: NaiveDateTime",
+                finished_at AS "finished_at: NaiveDateTime"
+            FROM jobs
+            WHERE ? IS NULL OR state = ?
+            ORDER BY id DESC
+            LIMIT ?"#,
+            state,
+            state,
+            LIST_LIMIT
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Job::from)
+        .collect())
+    }
+}
@@ -1,18 +1,39 @@
+mod backfill;
+mod check;
+mod dapi;
 mod database;
+mod duplicates;
+mod events;
+mod fetcher;
+mod jobs;
 mod media_processor;
+mod metatag;
+mod post;
+mod query;
+mod reconcile;
+mod redownload;
 mod search;
 mod server;
+mod site;
+mod sync;
+mod trash;
 mod upload;
 
 use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 use clap::Parser;
 use tokio::signal;
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{error, info};
 
 use crate::{
+    backfill::{BackfillOptions, backfill},
+    check::{CheckOptions, check},
     database::Database,
-    server::{create_router, spawn_server},
+    events::Events,
+    jobs::{JobQueue, spawn_workers},
+    server::{ServerOptions, create_router, spawn_server},
+    site::Site,
+    trash::spawn_purge,
 };
 
 #[derive(clap::Parser)]
@@ -27,11 +48,74 @@ struct Args {
 
     #[arg(default_value_t = false, long)]
     verbose: bool,
+
+    /// Hard link posts with identical content instead of storing the file twice
+    #[arg(default_value_t = false, long)]
+    dedupe: bool,
+
+    /// Move the files of deleted posts to the trash and only purge them after this many days
+    #[arg(long)]
+    trash_days: Option<u64>,
+
+    /// Allow the server to download posts itself from this site, e.g. https://rule34.xxx
+    #[arg(long, value_parser = parse_fetch_from)]
+    fetch_from: Option<String>,
+
+    /// Credentials for the API of that site, as `USER_ID:API_KEY` from the options page
+    #[arg(long, value_parser = parse_api_key)]
+    api_key: Option<(String, String)>,
+
+    /// How many uploads are processed at the same time
+    #[arg(default_value_t = 2, long)]
+    workers: usize,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Compute metadata that is missing for posts downloaded by older versions, then exit
+    Backfill {
+        /// How many posts are processed at the same time
+        #[arg(default_value_t = 4, long)]
+        jobs: usize,
+
+        /// Only list the posts that are missing metadata
+        #[arg(default_value_t = false, long)]
+        dry_run: bool,
+    },
+
+    /// Report differences between the database and the files in the library, then exit
+    Check {
+        /// Regenerate missing thumbnails, quarantine orphaned files and flag posts with missing or
+        /// broken files for re-download. Stop the server first, uploads it is storing would be
+        /// quarantined otherwise
+        #[arg(default_value_t = false, long)]
+        repair: bool,
+    },
+}
+
+fn parse_api_key(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once(':')
+        .map(|(user_id, api_key)| (user_id.to_string(), api_key.to_string()))
+        .ok_or("expected USER_ID:API_KEY".to_string())
+}
+
+fn parse_fetch_from(value: &str) -> Result<String, String> {
+    match Site::from_url(value) {
+        Some(site) if !site.is_gelbooru() => Err(format!(
+            "posts can't be fetched from {}, only from Gelbooru style sites like rule34.xxx",
+            site.as_str()
+        )),
+        _ => Ok(value.to_string()),
+    }
 }
 
-fn args() -> (Utf8PathBuf, bool) {
-    let args = Args::parse();
-    let path = normalize_path(&camino::absolute_utf8(args.path).expect("make path absolute"));
+fn args() -> Args {
+    let mut args = Args::parse();
+    let path = normalize_path(&camino::absolute_utf8(&args.path).expect("make path absolute"));
 
     std::fs::create_dir_all(path.join(".thumbs")).expect("create thumbs directory");
     std::fs::create_dir_all(path.join(".minis")).expect("create mini directory");
@@ -40,13 +124,15 @@ fn args() -> (Utf8PathBuf, bool) {
         panic!("{path} is not a directory");
     }
 
-    (path, args.verbose)
+    args.path = path;
+    args
 }
 
 #[tokio::main]
 async fn main() {
-    let (path, verbose) = args();
-    let logging_level = if verbose {
+    let args = args();
+    let path = &args.path;
+    let logging_level = if args.verbose {
         tracing::Level::DEBUG
     } else {
         tracing::Level::INFO
@@ -63,8 +149,46 @@ async fn main() {
         .await
         .expect("open database");
 
-    let router = create_router(&database, &path);
+    if let Some(command) = args.command {
+        let result = match command {
+            Command::Backfill { jobs, dry_run } => {
+                backfill(&database, path, BackfillOptions { jobs, dry_run }).await
+            }
+            Command::Check { repair } => check(&database, path, CheckOptions { repair }).await,
+        };
+        database.pool.close().await;
+        if let Err(err) = result {
+            error!("{err:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let options = ServerOptions {
+        dedupe: args.dedupe,
+        trash_days: args.trash_days,
+        fetch_base_url: args.fetch_from,
+        api_credentials: args.api_key,
+    };
+    let jobs = JobQueue::default();
+    let events = Events::new();
+    let worker_handles = spawn_workers(
+        &database,
+        path,
+        &options,
+        &jobs,
+        &events,
+        args.workers,
+        &shutdown_token,
+    )
+    .await
+    .expect("start upload workers");
+
+    let router = create_router(&database, path, options, &jobs, &events, &shutdown_token);
     let server_handle = spawn_server(router, &shutdown_token);
+    let purge_handle = args
+        .trash_days
+        .map(|days| spawn_purge(path, days, &shutdown_token));
 
     info!("Arueshalae server started");
     info!("The userscript can be installed from http://localhost:34343/arueshalae.user.js");
@@ -75,6 +199,12 @@ async fn main() {
     shutdown_signal.await;
     shutdown_token.cancel();
     _ = server_handle.await;
+    for worker_handle in worker_handles {
+        _ = worker_handle.await;
+    }
+    if let Some(purge_handle) = purge_handle {
+        _ = purge_handle.await;
+    }
     database.pool.close().await;
 }
 
@@ -1,15 +1,16 @@
-use std::{path::Path, process::Stdio};
+use std::{io::ErrorKind, path::Path, process::Stdio};
 
 use anyhow::{Context, Result, anyhow, bail};
 use camino::{Utf8Path, Utf8PathBuf};
 use infer::MatcherType;
+use serde::Deserialize;
 use tempfile::NamedTempFile;
 use tokio::{
     fs::{File, metadata},
     io::{AsyncReadExt, AsyncWriteExt},
     process::Command,
 };
-use tracing::debug;
+use tracing::{debug, warn};
 
 const HEADER_SIZE: usize = 0xFF;
 const JPEG_QUALITY: u8 = 90;
@@ -20,14 +21,77 @@ pub const MINI_SIZE: u32 = 350;
 pub struct MediaProcessorResult {
     pub file: NamedTempFile,
     pub thumb: Option<NamedTempFile>,
-    pub mime: &'static str,
-    pub extension: &'static str,
+    pub metadata: MediaMetadata,
+}
+
+/// Everything about a processed file that is stored with the post.
+pub struct MediaMetadata {
+    pub mime: String,
+    pub extension: String,
     pub original: bool,
+    /// dHash of the image or the video thumbnail, used to find near duplicates.
+    pub phash: Option<i64>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub file_size: Option<i64>,
+    /// Seconds, also set for animated gifs.
+    pub duration: Option<f64>,
+    pub frame_rate: Option<f64>,
+    pub codec: Option<String>,
+    /// Only known for videos.
+    pub has_audio: Option<bool>,
+}
+
+impl MediaMetadata {
+    fn set_probe(&mut self, probe: MediaProbe) {
+        self.width = probe.width;
+        self.height = probe.height;
+        self.duration = probe.duration;
+        self.frame_rate = probe.frame_rate;
+        self.codec = probe.codec;
+    }
+}
+
+/// Stream information reported by ffprobe.
+#[derive(Default)]
+pub struct MediaProbe {
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub duration: Option<f64>,
+    pub frame_rate: Option<f64>,
+    pub codec: Option<String>,
+    pub has_audio: bool,
 }
 
 impl MediaProcessorResult {
+    fn new(
+        file: NamedTempFile,
+        thumb: Option<NamedTempFile>,
+        mime: &str,
+        extension: &str,
+        original: bool,
+    ) -> Self {
+        Self {
+            file,
+            thumb,
+            metadata: MediaMetadata {
+                mime: mime.to_string(),
+                extension: extension.to_string(),
+                original,
+                phash: None,
+                width: None,
+                height: None,
+                file_size: None,
+                duration: None,
+                frame_rate: None,
+                codec: None,
+                has_audio: None,
+            },
+        }
+    }
+
     pub async fn commit(self, base_path: &Utf8Path, id: i64, external_id: i64) -> Result<()> {
-        let file_name = file_name(id, external_id, self.extension);
+        let file_name = file_name(id, external_id, &self.metadata.extension);
 
         let Self { file, thumb, .. } = self;
         move_file(file.path(), base_path.join(&file_name).as_path()).await?;
@@ -59,83 +123,55 @@ impl MediaProcessor {
             .await
             .context("failed to determine file type")?;
 
-        Ok(match file_type.matcher_type() {
+        let mut result = match file_type.matcher_type() {
             MatcherType::Video => processor.process_video(file_type).await?,
             MatcherType::Image => processor.process_image(file_type).await?,
             matched_type => bail!("Unsupported file type: {:#?}", matched_type),
-        })
-    }
+        };
 
-    async fn process_video(mut self, file_type: infer::Type) -> Result<MediaProcessorResult> {
-        let thumb_file = self.make_video_thumbnail().await?;
-
-        Ok(MediaProcessorResult {
-            file: self.file,
-            thumb: Some(thumb_file),
-            mime: file_type.mime_type(),
-            extension: file_type.extension(),
-            original: true,
-        })
-    }
+        result.metadata.file_size = Some(metadata(result.file.path()).await?.len() as i64);
+        if result.thumb.is_none() {
+            match probe_media(result.file.path()).await {
+                Ok(probe) => result.metadata.set_probe(probe),
+                Err(err) => warn!("Could not probe image: {err:#}"),
+            }
+        }
 
-    async fn make_video_thumbnail(&mut self) -> Result<NamedTempFile> {
-        let thumb_file = NamedTempFile::with_suffix(".jpeg")?;
-        let thumb_path = thumb_file.path();
-        let thumb_time = self.video_duration().await? * 0.1;
-        if !Command::new("ffmpeg")
-            .arg("-y")
-            .arg("-ss")
-            .arg(thumb_time.to_string())
-            .arg("-i")
-            .arg(self.file.path())
-            .arg("-frames:v")
-            .arg("1")
-            .arg("-q:v")
-            .arg("2")
-            .arg("-update")
-            .arg("1")
-            .arg(thumb_path)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .stdin(Stdio::null())
-            .status()
-            .await
-            .context("failed to start ffmpeg. is it installed?")?
-            .success()
-        {
-            bail!(
-                "ffmpeg failed to create thumbnail for {:?} -> {:?}",
-                self.file.path(),
-                thumb_path
-            )
+        let hashed_file = result.thumb.as_ref().unwrap_or(&result.file);
+        result.metadata.phash = match perceptual_hash(hashed_file.path()).await {
+            Ok(phash) => Some(phash),
+            Err(err) => {
+                warn!("Could not compute perceptual hash: {err:#}");
+                None
+            }
         };
-        Ok(thumb_file)
+
+        Ok(result)
     }
 
-    async fn video_duration(&self) -> Result<f32> {
-        let output = Command::new("ffprobe")
-            .arg("-v")
-            .arg("quiet")
-            .arg("-i")
-            .arg(self.file.path())
-            .arg("-show_entries")
-            .arg("format=duration")
-            .arg("-of")
-            .arg("csv=p=0")
-            .stdin(Stdio::null())
-            .output()
-            .await
-            .context("failed to start ffmpeg. is it installed?")?;
+    async fn process_video(mut self, file_type: infer::Type) -> Result<MediaProcessorResult> {
+        let probe = probe_media(self.file.path()).await?;
+        let duration = probe
+            .duration
+            .ok_or(anyhow!("ffprobe did not return video duration"))?;
+        let thumb_file = self.make_video_thumbnail(duration).await?;
 
-        if !output.status.success() {
-            bail!("ffprobe failed to get length");
-        }
+        let mut result = MediaProcessorResult::new(
+            self.file,
+            Some(thumb_file),
+            file_type.mime_type(),
+            file_type.extension(),
+            true,
+        );
+        result.metadata.has_audio = Some(probe.has_audio);
+        result.metadata.set_probe(probe);
+        Ok(result)
+    }
 
-        Ok(String::from_utf8(output.stdout)?
-            .lines()
-            .next()
-            .ok_or(anyhow!("ffprobe did not return video duration"))?
-            .parse()?)
+    async fn make_video_thumbnail(&mut self, duration: f64) -> Result<NamedTempFile> {
+        let thumb_file = NamedTempFile::with_suffix(".jpeg")?;
+        video_thumbnail(self.file.path(), thumb_file.path(), duration).await?;
+        Ok(thumb_file)
     }
 
     async fn process_image(self, file_type: infer::Type) -> Result<MediaProcessorResult> {
@@ -144,13 +180,13 @@ impl MediaProcessor {
         {
             self.compress_image(file_type).await
         } else {
-            Ok(MediaProcessorResult {
-                file: self.file,
-                thumb: None,
-                mime: file_type.mime_type(),
-                extension: file_type.extension(),
-                original: true,
-            })
+            Ok(MediaProcessorResult::new(
+                self.file,
+                None,
+                file_type.mime_type(),
+                file_type.extension(),
+                true,
+            ))
         }
     }
 
@@ -177,42 +213,74 @@ impl MediaProcessor {
         let original_size = metadata(self.file.path()).await?.len();
 
         if compressed_size < original_size {
-            Ok(MediaProcessorResult {
-                file: compressed,
-                thumb: None,
-                mime: "image/jpeg",
-                extension: "jpeg",
-                original: false,
-            })
+            Ok(MediaProcessorResult::new(
+                compressed,
+                None,
+                "image/jpeg",
+                "jpeg",
+                false,
+            ))
         } else {
-            Ok(MediaProcessorResult {
-                file: self.file,
-                thumb: None,
-                mime: file_type.mime_type(),
-                extension: file_type.extension(),
-                original: true,
-            })
+            Ok(MediaProcessorResult::new(
+                self.file,
+                None,
+                file_type.mime_type(),
+                file_type.extension(),
+                true,
+            ))
         }
     }
 
     async fn file_type(&self) -> Result<infer::Type> {
-        let mut buf = [0; HEADER_SIZE];
-        let bytes_read = File::open(&self.file.path())
-            .await
-            .context("Failed to open file for type infer")?
-            .read_exact(&mut buf)
-            .await
-            .context("Failed to read header for type infer")?;
-        if bytes_read == 0 {
-            bail!("{:?} appears to be empty", &self.file.path())
-        }
-        infer::Infer::new().get(&buf).ok_or(anyhow!(
-            "Could not infer file type for {:?}",
-            &self.file.path()
-        ))
+        file_type(self.file.path()).await
     }
 }
 
+pub async fn file_type(path: &Path) -> Result<infer::Type> {
+    let mut buf = [0; HEADER_SIZE];
+    let bytes_read = File::open(path)
+        .await
+        .context("Failed to open file for type infer")?
+        .read_exact(&mut buf)
+        .await
+        .context("Failed to read header for type infer")?;
+    if bytes_read == 0 {
+        bail!("{:?} appears to be empty", path)
+    }
+    infer::Infer::new()
+        .get(&buf)
+        .ok_or(anyhow!("Could not infer file type for {:?}", path))
+}
+
+/// Saves a frame from 10% into the video as the thumbnail.
+pub async fn video_thumbnail(video_path: &Path, thumb_path: &Path, duration: f64) -> Result<()> {
+    let thumb_time = duration * 0.1;
+    if !Command::new("ffmpeg")
+        .arg("-y")
+        .arg("-ss")
+        .arg(thumb_time.to_string())
+        .arg("-i")
+        .arg(video_path)
+        .arg("-frames:v")
+        .arg("1")
+        .arg("-q:v")
+        .arg("2")
+        .arg("-update")
+        .arg("1")
+        .arg(thumb_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .stdin(Stdio::null())
+        .status()
+        .await
+        .context("failed to start ffmpeg. is it installed?")?
+        .success()
+    {
+        bail!("ffmpeg failed to create thumbnail for {video_path:?} -> {thumb_path:?}")
+    };
+    Ok(())
+}
+
 pub async fn mini_thumb(
     name: &str,
     original_path: &Utf8Path,
@@ -245,7 +313,159 @@ pub async fn mini_thumb(
     Ok(new_path)
 }
 
-async fn move_file(from: &Path, to: &Utf8Path) -> Result<()> {
+#[derive(Deserialize)]
+struct FfprobeOutput {
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+    format: Option<FfprobeFormat>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeStream {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    width: Option<i64>,
+    height: Option<i64>,
+    avg_frame_rate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeFormat {
+    duration: Option<String>,
+}
+
+pub async fn probe_media(path: &Path) -> Result<MediaProbe> {
+    let output = Command::new("ffprobe")
+        .arg("-v")
+        .arg("quiet")
+        .arg("-print_format")
+        .arg("json")
+        .arg("-show_format")
+        .arg("-show_streams")
+        .arg("-i")
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("failed to start ffprobe. is it installed?")?;
+
+    if !output.status.success() {
+        bail!("ffprobe failed to probe {path:?}");
+    }
+
+    let output: FfprobeOutput =
+        serde_json::from_slice(&output.stdout).context("ffprobe returned invalid json")?;
+    let video = output
+        .streams
+        .iter()
+        .find(|stream| stream.codec_type.as_deref() == Some("video"));
+
+    Ok(MediaProbe {
+        width: video.and_then(|stream| stream.width),
+        height: video.and_then(|stream| stream.height),
+        duration: output
+            .format
+            .and_then(|format| format.duration)
+            .and_then(|duration| duration.parse().ok()),
+        frame_rate: video
+            .and_then(|stream| stream.avg_frame_rate.as_deref())
+            .and_then(parse_frame_rate),
+        codec: video.and_then(|stream| stream.codec_name.clone()),
+        has_audio: output
+            .streams
+            .iter()
+            .any(|stream| stream.codec_type.as_deref() == Some("audio")),
+    })
+}
+
+/// ffprobe reports frame rates as fractions like `30000/1001`, or `0/0` if there is none.
+fn parse_frame_rate(value: &str) -> Option<f64> {
+    let (numerator, denominator) = value.split_once('/')?;
+    let (numerator, denominator): (f64, f64) = (numerator.parse().ok()?, denominator.parse().ok()?);
+    (numerator > 0.0 && denominator > 0.0).then(|| numerator / denominator)
+}
+
+/// Computes a 64 bit difference hash: the image is shrunk to 9x8 grey pixels and every bit tells
+/// whether a pixel is brighter than its right neighbour. Similar images have hashes with a small
+/// hamming distance.
+pub async fn perceptual_hash(path: &Path) -> Result<i64> {
+    let output = Command::new("ffmpeg")
+        .arg("-v")
+        .arg("quiet")
+        .arg("-i")
+        .arg(path)
+        .arg("-vf")
+        .arg("scale=9:8:flags=area,format=gray")
+        .arg("-frames:v")
+        .arg("1")
+        .arg("-f")
+        .arg("rawvideo")
+        .arg("-")
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("failed to start ffmpeg. is it installed?")?;
+
+    if !output.status.success() || output.stdout.len() != 9 * 8 {
+        bail!("ffmpeg failed to scale {path:?} for hashing");
+    }
+
+    let mut hash = 0u64;
+    for row in output.stdout.chunks_exact(9) {
+        for pixels in row.windows(2) {
+            hash = (hash << 1) | u64::from(pixels[0] > pixels[1]);
+        }
+    }
+    Ok(hash as i64)
+}
+
+/// Gives a post the files of another post with the same content. Files are hard linked so the
+/// content is only stored once, falling back to a copy if linking isn't possible.
+pub async fn link_post_files(base_path: &Utf8Path, from: &str, to: &str) -> Result<()> {
+    let thumbs = base_path.join(".thumbs");
+    for (source, target) in [
+        (base_path.join(from), base_path.join(to)),
+        (
+            thumbs.join(format!("{from}.jpeg")),
+            thumbs.join(format!("{to}.jpeg")),
+        ),
+    ] {
+        if !source.is_file() {
+            continue;
+        }
+        if tokio::fs::hard_link(&source, &target).await.is_err() {
+            tokio::fs::copy(&source, &target)
+                .await
+                .with_context(|| format!("failed to copy {source} to {target}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the file of a post together with its video thumbnail and mini thumbnail.
+pub async fn remove_post_files(
+    base_path: &Utf8Path,
+    id: i64,
+    external_id: i64,
+    extension: &str,
+) -> Result<()> {
+    let name = file_name(id, external_id, extension);
+    for path in [
+        base_path.join(&name),
+        base_path.join(".thumbs").join(format!("{name}.jpeg")),
+        base_path.join(".minis").join(format!("mini_{name}.jpeg")),
+    ] {
+        match tokio::fs::remove_file(&path).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                return Err(err).context(format!("failed to remove {path}"));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub async fn move_file(from: &Path, to: &Utf8Path) -> Result<()> {
     if tokio::fs::rename(from, to).await.is_err() {
         let mut temp_file = File::open(from).await?;
         let mut final_file = File::create(to).await?;
@@ -0,0 +1,213 @@
+use std::str::FromStr;
+
+use chrono::NaiveDate;
+
+use crate::upload::Rating;
+
+/// Search terms that filter on post metadata instead of tags, written as `key:value`.
+///
+/// Numeric and date values can be compared with `key:>value`, `key:>=value`, `key:<value`,
+/// `key:<=value` or given as an inclusive range with `key:from..to`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetaTag {
+    Type(MediaType),
+    Mime(String),
+    Original(bool),
+    Added(Comparison<NaiveDate>),
+    Id(Comparison<i64>),
+    TagCount(Comparison<i64>),
+    Width(Comparison<i64>),
+    Height(Comparison<i64>),
+    /// Seconds.
+    Duration(Comparison<f64>),
+    /// Bytes, the value can use a `kb`, `mb` or `gb` suffix.
+    FileSize(Comparison<i64>),
+    Audio(bool),
+    Rating(Rating),
+    Score(Comparison<i64>),
+    /// `*` wildcard pattern.
+    Source(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediaType {
+    Image,
+    Video,
+    Gif,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Comparison<T> {
+    Eq(T),
+    Lt(T),
+    Le(T),
+    Gt(T),
+    Ge(T),
+    Range(T, T),
+}
+
+/// Sort order of search results, written as `order:key`. Every order is descending unless the key
+/// is suffixed with `_asc`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Order {
+    pub key: OrderKey,
+    pub ascending: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OrderKey {
+    /// Order in which the posts were downloaded.
+    Added,
+    Id,
+    TagCount,
+    FileSize,
+    Score,
+    /// Shuffled, but stable for the same seed so the results can be paged through.
+    Random,
+}
+
+impl Default for Order {
+    fn default() -> Self {
+        Self {
+            key: OrderKey::Added,
+            ascending: false,
+        }
+    }
+}
+
+impl Order {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (key, ascending) = if let Some(key) = value.strip_suffix("_asc") {
+            (key, true)
+        } else {
+            (value.strip_suffix("_desc").unwrap_or(value), false)
+        };
+
+        let key = match key {
+            "added" => OrderKey::Added,
+            "id" => OrderKey::Id,
+            "tagcount" => OrderKey::TagCount,
+            "filesize" => OrderKey::FileSize,
+            "score" => OrderKey::Score,
+            "random" => OrderKey::Random,
+            _ => return None,
+        };
+
+        Some(Self { key, ascending })
+    }
+}
+
+impl MetaTag {
+    /// Returns `Ok(None)` if `word` is not a metatag. Tags can contain colons, so unknown keys are
+    /// searched as regular tags.
+    pub fn parse(word: &str) -> Result<Option<Self>, String> {
+        let Some((key, value)) = word.split_once(':') else {
+            return Ok(None);
+        };
+
+        Ok(Some(match key {
+            "type" => Self::Type(match value {
+                "image" => MediaType::Image,
+                "video" => MediaType::Video,
+                "gif" => MediaType::Gif,
+                _ => {
+                    return Err(format!(
+                        "unknown type '{value}', expected image, video or gif"
+                    ));
+                }
+            }),
+            "mime" => Self::Mime(value.to_string()),
+            "original" => Self::Original(parse_bool(key, value)?),
+            "added" => Self::Added(Comparison::parse(key, value)?),
+            "id" => Self::Id(Comparison::parse(key, value)?),
+            "tagcount" => Self::TagCount(Comparison::parse(key, value)?),
+            "width" => Self::Width(Comparison::parse(key, value)?),
+            "height" => Self::Height(Comparison::parse(key, value)?),
+            "duration" => Self::Duration(Comparison::parse(key, value)?),
+            "filesize" => {
+                Self::FileSize(Comparison::<FileSize>::parse(key, value)?.map(|size| size.0))
+            }
+            "audio" | "sound" => Self::Audio(parse_bool(key, value)?),
+            "rating" => Self::Rating(Rating::parse(value).ok_or_else(|| {
+                format!("unknown rating '{value}', expected safe, questionable or explicit")
+            })?),
+            "score" => Self::Score(Comparison::parse(key, value)?),
+            "source" => Self::Source(value.to_string()),
+            _ => return Ok(None),
+        }))
+    }
+}
+
+impl<T> Comparison<T> {
+    fn map<U>(self, f: impl Fn(T) -> U) -> Comparison<U> {
+        match self {
+            Self::Eq(value) => Comparison::Eq(f(value)),
+            Self::Lt(value) => Comparison::Lt(f(value)),
+            Self::Le(value) => Comparison::Le(f(value)),
+            Self::Gt(value) => Comparison::Gt(f(value)),
+            Self::Ge(value) => Comparison::Ge(f(value)),
+            Self::Range(from, to) => Comparison::Range(f(from), f(to)),
+        }
+    }
+}
+
+impl<T: FromStr> Comparison<T> {
+    fn parse(key: &str, value: &str) -> Result<Self, String> {
+        let parse_value = |value: &str| {
+            value
+                .parse::<T>()
+                .map_err(|_| format!("invalid value '{value}' for {key}"))
+        };
+
+        Ok(if let Some(value) = value.strip_prefix(">=") {
+            Self::Ge(parse_value(value)?)
+        } else if let Some(value) = value.strip_prefix("<=") {
+            Self::Le(parse_value(value)?)
+        } else if let Some(value) = value.strip_prefix('>') {
+            Self::Gt(parse_value(value)?)
+        } else if let Some(value) = value.strip_prefix('<') {
+            Self::Lt(parse_value(value)?)
+        } else if let Some((from, to)) = value.split_once("..") {
+            match (from, to) {
+                ("", "") => return Err(format!("empty range for {key}")),
+                ("", to) => Self::Le(parse_value(to)?),
+                (from, "") => Self::Ge(parse_value(from)?),
+                (from, to) => Self::Range(parse_value(from)?, parse_value(to)?),
+            }
+        } else {
+            Self::Eq(parse_value(value.strip_prefix('=').unwrap_or(value))?)
+        })
+    }
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" => Ok(false),
+        _ => Err(format!(
+            "invalid value '{value}' for {key}, expected true or false"
+        )),
+    }
+}
+
+struct FileSize(i64);
+
+impl FromStr for FileSize {
+    /// Reported as an invalid value by [`Comparison::parse`].
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.to_ascii_lowercase();
+        let (number, unit) = [("kb", 1 << 10), ("mb", 1 << 20), ("gb", 1 << 30)]
+            .into_iter()
+            .find_map(|(suffix, unit)| Some((value.strip_suffix(suffix)?.to_string(), unit)))
+            .unwrap_or((value, 1));
+        number
+            .trim_end_matches('b')
+            .parse::<i64>()
+            .ok()
+            .and_then(|number| number.checked_mul(unit))
+            .map(Self)
+            .ok_or(())
+    }
+}
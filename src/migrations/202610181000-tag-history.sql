@@ -0,0 +1,10 @@
+CREATE TABLE post_tag_history (
+  id INTEGER PRIMARY KEY NOT NULL,
+  post_id INTEGER NOT NULL REFERENCES posts(id),
+  tag_id INTEGER NOT NULL REFERENCES tags(id),
+  action TEXT NOT NULL,
+
+  changed_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IDX_post_tag_history_post_id ON post_tag_history(post_id);
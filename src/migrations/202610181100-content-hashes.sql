@@ -0,0 +1,4 @@
+ALTER TABLE posts ADD COLUMN sha256 TEXT;
+ALTER TABLE posts ADD COLUMN md5 TEXT;
+
+CREATE INDEX IDX_posts_sha256 ON posts(sha256);
@@ -0,0 +1,7 @@
+ALTER TABLE posts ADD COLUMN width INTEGER;
+ALTER TABLE posts ADD COLUMN height INTEGER;
+ALTER TABLE posts ADD COLUMN file_size INTEGER;
+ALTER TABLE posts ADD COLUMN duration REAL;
+ALTER TABLE posts ADD COLUMN frame_rate REAL;
+ALTER TABLE posts ADD COLUMN codec TEXT;
+ALTER TABLE posts ADD COLUMN has_audio BOOLEAN;
@@ -0,0 +1,6 @@
+CREATE TABLE redownload (
+  post_id INTEGER PRIMARY KEY NOT NULL REFERENCES posts(id),
+  reason TEXT NOT NULL,
+
+  flagged_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
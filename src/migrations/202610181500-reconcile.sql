@@ -0,0 +1,7 @@
+CREATE TABLE reconcile_favorites (
+  session TEXT NOT NULL,
+  external_id INTEGER NOT NULL,
+
+  added_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (session, external_id)
+);
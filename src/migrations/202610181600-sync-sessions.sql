@@ -0,0 +1,25 @@
+CREATE TABLE sync_sessions (
+  id INTEGER PRIMARY KEY NOT NULL,
+  kind TEXT NOT NULL,
+  user_id INTEGER NOT NULL,
+  goal INTEGER NOT NULL,
+
+  -- Favorites page offset of the last completed page, pages are walked from the oldest favorites
+  pid INTEGER,
+  downloaded INTEGER NOT NULL DEFAULT 0,
+
+  started_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  finished_at DATETIME
+);
+
+CREATE TABLE sync_errors (
+  id INTEGER PRIMARY KEY NOT NULL,
+  session_id INTEGER NOT NULL REFERENCES sync_sessions(id),
+  post_id INTEGER,
+  error TEXT NOT NULL,
+
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IDX_sync_errors_session_id ON sync_errors(session_id);
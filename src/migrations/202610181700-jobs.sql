@@ -0,0 +1,20 @@
+CREATE TABLE jobs (
+  id INTEGER PRIMARY KEY NOT NULL,
+  external_id INTEGER NOT NULL,
+  state TEXT NOT NULL DEFAULT 'queued',
+
+  -- Everything of the upload except the file, which is kept in .jobs/<id> until it's processed
+  sha256 TEXT NOT NULL,
+  md5 TEXT NOT NULL,
+  tags TEXT,
+  replace BOOLEAN NOT NULL,
+
+  result TEXT,
+  error TEXT,
+
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  started_at DATETIME,
+  finished_at DATETIME
+);
+
+CREATE INDEX IDX_jobs_state ON jobs(state);
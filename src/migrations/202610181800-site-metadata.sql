@@ -0,0 +1,15 @@
+-- What the site says about a post.
+--
+-- The md5 the site reports is deliberately not stored in a column of its own. Uploads whose md5
+-- doesn't match it are rejected, so for every post that came with one it is the same as posts.md5.
+-- Posts downloaded without it (older userscripts, or the fetcher when the API can't be reached)
+-- only have the md5 of the file that was stored, nothing the site said to compare it to later.
+ALTER TABLE posts ADD COLUMN rating TEXT;
+ALTER TABLE posts ADD COLUMN score INTEGER;
+ALTER TABLE posts ADD COLUMN source TEXT;
+ALTER TABLE posts ADD COLUMN parent_id INTEGER;
+
+CREATE INDEX IDX_posts_rating ON posts(rating);
+
+-- SiteMetadata of the upload as JSON
+ALTER TABLE jobs ADD COLUMN metadata TEXT;
@@ -0,0 +1,51 @@
+-- Post ids are only unique within a site. SQLite can't drop the unique constraint on external_id,
+-- so the table is rebuilt.
+CREATE TABLE posts_new (
+  id INTEGER PRIMARY KEY NOT NULL,
+  site TEXT NOT NULL DEFAULT 'rule34',
+  external_id INTEGER NOT NULL,
+
+  extension TEXT NOT NULL,
+  mime TEXT NOT NULL,
+  original BOOLEAN NOT NULL,
+
+  added_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  sha256 TEXT,
+  md5 TEXT,
+  phash INTEGER,
+
+  width INTEGER,
+  height INTEGER,
+  file_size INTEGER,
+  duration REAL,
+  frame_rate REAL,
+  codec TEXT,
+  has_audio BOOLEAN,
+
+  rating TEXT,
+  score INTEGER,
+  source TEXT,
+  parent_id INTEGER,
+
+  UNIQUE (site, external_id)
+);
+
+INSERT INTO posts_new (
+  id, external_id, extension, mime, original, added_at, sha256, md5, phash, width, height,
+  file_size, duration, frame_rate, codec, has_audio, rating, score, source, parent_id
+)
+SELECT
+  id, external_id, extension, mime, original, added_at, sha256, md5, phash, width, height,
+  file_size, duration, frame_rate, codec, has_audio, rating, score, source, parent_id
+FROM posts;
+
+DROP TABLE posts;
+ALTER TABLE posts_new RENAME TO posts;
+
+CREATE INDEX IDX_posts_sha256 ON posts(sha256);
+CREATE INDEX IDX_posts_rating ON posts(rating);
+
+ALTER TABLE jobs ADD COLUMN site TEXT NOT NULL DEFAULT 'rule34';
+
+ALTER TABLE sync_sessions ADD COLUMN site TEXT NOT NULL DEFAULT 'rule34';
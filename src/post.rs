@@ -0,0 +1,293 @@
+use anyhow::Result;
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use camino::Utf8Path;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::info;
+
+use crate::{
+    database::Database,
+    json_ok,
+    media_processor::{file_name, remove_post_files},
+    server::{AppResult, AppState, NotFound, ServerOptions},
+    site::{Site, SiteQuery},
+    trash::trash_post_files,
+    upload::{StoredPost, TagKind},
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostDetails {
+    site: String,
+    #[serde(rename = "id")]
+    external_id: i64,
+    mime: String,
+    extension: String,
+    original: bool,
+    added_at: NaiveDateTime,
+    file_name: String,
+    /// Size of the stored file, `None` if it is unknown and missing on disk.
+    file_size: Option<i64>,
+    width: Option<i64>,
+    height: Option<i64>,
+    duration: Option<f64>,
+    frame_rate: Option<f64>,
+    codec: Option<String>,
+    has_audio: Option<bool>,
+    rating: Option<String>,
+    score: Option<i64>,
+    source: Option<String>,
+    parent_id: Option<i64>,
+    sha256: Option<String>,
+    md5: Option<String>,
+    tags: GroupedTags,
+}
+
+#[derive(Serialize, Default)]
+pub struct GroupedTags {
+    copyright: Vec<String>,
+    character: Vec<String>,
+    artist: Vec<String>,
+    general: Vec<String>,
+    metadata: Vec<String>,
+}
+
+impl GroupedTags {
+    fn push(&mut self, name: String, kind: TagKind) {
+        match kind {
+            TagKind::Copyright => self.copyright.push(name),
+            TagKind::Character => self.character.push(name),
+            TagKind::Artist => self.artist.push(name),
+            TagKind::General => self.general.push(name),
+            TagKind::Metadata => self.metadata.push(name),
+        }
+    }
+}
+
+pub async fn get_post(
+    State(AppState {
+        database,
+        base_path,
+        ..
+    }): State<AppState>,
+    Path(post_id): Path<i64>,
+    Query(SiteQuery { site }): Query<SiteQuery>,
+) -> AppResult<Json<PostDetails>> {
+    let Some(mut details) = database.get_post_details(site, post_id).await? else {
+        return Err(NotFound(format!("post {post_id} not found in database")).into());
+    };
+
+    if details.file_size.is_none() {
+        details.file_size = tokio::fs::metadata(base_path.join(&details.file_name))
+            .await
+            .ok()
+            .map(|metadata| metadata.len() as i64);
+    }
+
+    Ok(Json(details))
+}
+
+pub async fn get_post_history(
+    State(AppState { database, .. }): State<AppState>,
+    Path(post_id): Path<i64>,
+    Query(SiteQuery { site }): Query<SiteQuery>,
+) -> AppResult<Json<Value>> {
+    let Some(history) = database.get_post_history(site, post_id).await? else {
+        return Err(NotFound(format!("post {post_id} not found in database")).into());
+    };
+    json_ok!({ "history": history })
+}
+
+/// Removes a post with its tags, history and files. With `--trash-days` the files are moved to the
+/// trash instead of being deleted right away.
+pub async fn delete_post(
+    State(AppState {
+        database,
+        base_path,
+        options,
+        ..
+    }): State<AppState>,
+    Path(post_id): Path<i64>,
+    Query(SiteQuery { site }): Query<SiteQuery>,
+) -> AppResult<Json<Value>> {
+    let Some(post) = database.get_stored_post(site, post_id).await? else {
+        return Err(NotFound(format!("post {post_id} not found in database")).into());
+    };
+    remove_post(&database, &base_path, &options, post, site, post_id).await?;
+    json_ok!({ "ok": true, "trashed": options.trash_days.is_some() })
+}
+
+pub async fn remove_post(
+    database: &Database,
+    base_path: &Utf8Path,
+    options: &ServerOptions,
+    StoredPost { id, extension }: StoredPost,
+    site: Site,
+    external_id: i64,
+) -> Result<()> {
+    // The files go first, a post whose files couldn't be removed is still in the database instead
+    // of leaving them orphaned.
+    if options.trash_days.is_some() {
+        let trash = trash_post_files(base_path, id, external_id, &extension).await?;
+        database.delete_post(id).await?;
+        info!(
+            "Deleted {}, its files were moved to {trash}",
+            site.post_url(external_id)
+        );
+    } else {
+        remove_post_files(base_path, id, external_id, &extension).await?;
+        database.delete_post(id).await?;
+        info!("Deleted {}", site.post_url(external_id));
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagHistoryEntry {
+    tag: String,
+    kind: String,
+    action: String,
+    changed_at: NaiveDateTime,
+}
+
+struct PostRow {
+    id: i64,
+    site: String,
+    external_id: i64,
+    mime: String,
+    extension: String,
+    original: bool,
+    added_at: NaiveDateTime,
+    file_size: Option<i64>,
+    width: Option<i64>,
+    height: Option<i64>,
+    duration: Option<f64>,
+    frame_rate: Option<f64>,
+    codec: Option<String>,
+    has_audio: Option<bool>,
+    rating: Option<String>,
+    score: Option<i64>,
+    source: Option<String>,
+    parent_id: Option<i64>,
+    sha256: Option<String>,
+    md5: Option<String>,
+}
+
+impl Database {
+    async fn delete_post(&self, id: i64) -> Result<()> {
+        let mut trx = self.pool.begin().await?;
+        sqlx::query!("DELETE FROM post_tags WHERE post_id = ?", id)
+            .execute(&mut *trx)
+            .await?;
+        sqlx::query!("DELETE FROM post_tag_history WHERE post_id = ?", id)
+            .execute(&mut *trx)
+            .await?;
+        sqlx::query!("DELETE FROM redownload WHERE post_id = ?", id)
+            .execute(&mut *trx)
+            .await?;
+        sqlx::query!("DELETE FROM posts WHERE id = ?", id)
+            .execute(&mut *trx)
+            .await?;
+        trx.commit().await?;
+        Ok(())
+    }
+
+    /// Returns the tag changes of a post, newest first, or `None` if the post doesn't exist.
+    async fn get_post_history(
+        &self,
+        site: Site,
+        external_id: i64,
+    ) -> Result<Option<Vec<TagHistoryEntry>>> {
+        let site = site.as_str();
+        let Some(id) = sqlx::query_scalar!(
+            "SELECT id FROM posts WHERE site = ? AND external_id = ?",
+            site,
+            external_id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(
+            sqlx::query_as!(
+                TagHistoryEntry,
+                r#"SELECT t.name AS tag, t.kind, h.action, h.changed_at
+                FROM post_tag_history h
+                JOIN tags t ON t.id = h.tag_id
+                WHERE h.post_id = ?
+                ORDER BY h.id DESC"#,
+                id
+            )
+            .fetch_all(&self.pool)
+            .await?,
+        ))
+    }
+
+    async fn get_post_details(&self, site: Site, external_id: i64) -> Result<Option<PostDetails>> {
+        let site = site.as_str();
+        let Some(post) = sqlx::query_as!(
+            PostRow,
+            r#"SELECT id, site, external_id, mime, extension, original, added_at, file_size,
+                width, height, duration, frame_rate, codec, has_audio, rating, score, source,
+                parent_id, sha256, md5
+            FROM posts
+            WHERE site = ? AND external_id = ?"#,
+            site,
+            external_id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let tags = sqlx::query!(
+            r#"SELECT t.name, t.kind
+            FROM post_tags pt
+            JOIN tags t ON t.id = pt.tag_id
+            WHERE pt.post_id = ?
+            ORDER BY t.name"#,
+            post.id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut grouped_tags = GroupedTags::default();
+        for tag in tags {
+            if let Some(kind) = TagKind::parse(&tag.kind) {
+                grouped_tags.push(tag.name, kind);
+            }
+        }
+
+        Ok(Some(PostDetails {
+            file_name: file_name(post.id, post.external_id, &post.extension),
+            site: post.site,
+            external_id: post.external_id,
+            mime: post.mime,
+            extension: post.extension,
+            original: post.original,
+            added_at: post.added_at,
+            file_size: post.file_size,
+            width: post.width,
+            height: post.height,
+            duration: post.duration,
+            frame_rate: post.frame_rate,
+            codec: post.codec,
+            has_audio: post.has_audio,
+            rating: post.rating,
+            score: post.score,
+            source: post.source,
+            parent_id: post.parent_id,
+            sha256: post.sha256,
+            md5: post.md5,
+            tags: grouped_tags,
+        }))
+    }
+}
@@ -0,0 +1,498 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{metatag::MetaTag, upload::TagKind};
+
+/// Deepest nesting of groups and negations, deeper queries would overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+/// Parsed form of a search query.
+///
+/// Terms separated by whitespace are combined with AND, `~` between two terms (or groups) combines
+/// them with OR and `-` negates the following term or group. Parentheses group sub expressions.
+/// `~` binds tighter than the whitespace between terms, so `cat ~ fox -comic` means
+/// `(cat ~ fox) -comic`. Like on the booru sites, prefixing several terms with `~` (`~cat ~fox`)
+/// ORs those terms together while the rest of the group is still required. Tags can be restricted
+/// to a [`TagKind`] with a qualifier like `artist:name`, and `artist:*` matches any artist tag.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// Matches every post. Produced by an empty query.
+    All,
+    Tag {
+        name: String,
+        kind: Option<TagKind>,
+    },
+    /// A tag pattern where `*` matches any number of characters.
+    Wildcard {
+        pattern: String,
+        kind: Option<TagKind>,
+        position: usize,
+    },
+    /// Any tag of the given kind.
+    Kind(TagKind),
+    /// Any of the given tag ids. Wildcards are expanded into this before searching.
+    TagIds(Vec<i64>),
+    Meta(MetaTag),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryError {
+    #[serde(rename = "error")]
+    pub message: String,
+    /// Byte offset into the query the error refers to.
+    pub position: usize,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+impl Expr {
+    /// Collects all wildcard terms so they can be expanded in place.
+    pub fn wildcards_mut(&mut self) -> Vec<&mut Expr> {
+        let mut wildcards = Vec::new();
+        self.collect_wildcards(&mut wildcards);
+        wildcards
+    }
+
+    fn collect_wildcards<'a>(&'a mut self, wildcards: &mut Vec<&'a mut Expr>) {
+        match self {
+            Expr::Wildcard { .. } => wildcards.push(self),
+            Expr::Not(expr) => expr.collect_wildcards(wildcards),
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                for expr in exprs {
+                    expr.collect_wildcards(wildcards);
+                }
+            }
+            Expr::All | Expr::Tag { .. } | Expr::Kind(_) | Expr::TagIds(_) | Expr::Meta(_) => {}
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Expr, QueryError> {
+    let tokens = tokenize(input);
+    if tokens.is_empty() {
+        return Ok(Expr::All);
+    }
+
+    let mut parser = Parser {
+        tokens,
+        index: 0,
+        end: input.len(),
+        depth: 0,
+    };
+    let expr = parser.parse_and()?;
+
+    if let Some(token) = parser.peek() {
+        return Err(parser.error_at(token, "unmatched ')'"));
+    }
+
+    Ok(expr)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    LParen,
+    RParen,
+    Not,
+    /// A `~` directly in front of a term.
+    OrPrefix,
+    /// A standalone `~` between two terms.
+    Or,
+    Word(String),
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    position: usize,
+}
+
+fn tokenize(input: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+
+    for (start, word) in split_words(input) {
+        if word == "~" {
+            tokens.push(Token {
+                kind: TokenKind::Or,
+                position: start,
+            });
+            continue;
+        }
+
+        let mut position = start;
+        let mut rest = word;
+        loop {
+            let kind = match rest.as_bytes().first() {
+                Some(b'(') => TokenKind::LParen,
+                Some(b'-') => TokenKind::Not,
+                Some(b'~') => TokenKind::OrPrefix,
+                _ => break,
+            };
+            tokens.push(Token { kind, position });
+            position += 1;
+            rest = &rest[1..];
+        }
+
+        // Tags may contain parentheses themselves (`saber_(fate)`), so only closing parentheses
+        // that are not balanced by an opening one inside the word end a group.
+        let mut balance = rest.matches('(').count() as isize - rest.matches(')').count() as isize;
+        let mut closing = 0;
+        while balance < 0 && rest.ends_with(')') {
+            rest = &rest[..rest.len() - 1];
+            balance += 1;
+            closing += 1;
+        }
+
+        if !rest.is_empty() {
+            tokens.push(Token {
+                kind: TokenKind::Word(rest.to_string()),
+                position,
+            });
+        }
+        for i in 0..closing {
+            tokens.push(Token {
+                kind: TokenKind::RParen,
+                position: position + rest.len() + i,
+            });
+        }
+    }
+
+    tokens
+}
+
+/// Splits the query on whitespace, keeping the byte offset of every word.
+pub fn split_words(input: &str) -> impl Iterator<Item = (usize, &str)> {
+    input
+        .split_whitespace()
+        .map(move |word| (word.as_ptr() as usize - input.as_ptr() as usize, word))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+    end: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    fn error_at(&self, token: &Token, message: &str) -> QueryError {
+        QueryError {
+            message: message.to_string(),
+            position: token.position,
+        }
+    }
+
+    fn error_here(&self, message: &str) -> QueryError {
+        QueryError {
+            message: message.to_string(),
+            position: self.peek().map_or(self.end, |token| token.position),
+        }
+    }
+
+    /// Counts a group or negation that starts at `token`, see [`Self::leave`].
+    fn enter(&mut self, token: &Token) -> Result<(), QueryError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error_at(token, "query is nested too deeply"));
+        }
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, QueryError> {
+        let mut items = Vec::new();
+        let mut alternatives = Vec::new();
+
+        while let Some(token) = self.peek() {
+            match token.kind {
+                TokenKind::RParen => break,
+                TokenKind::OrPrefix => {
+                    self.next();
+                    alternatives.push(self.parse_or()?);
+                }
+                _ => items.push(self.parse_or()?),
+            }
+        }
+
+        if !alternatives.is_empty() {
+            items.push(flatten(alternatives, Expr::Or));
+        }
+        if items.is_empty() {
+            return Err(self.error_here("expected a search term"));
+        }
+
+        Ok(flatten(items, Expr::And))
+    }
+
+    /// A term, or several joined by standalone `~`.
+    fn parse_or(&mut self) -> Result<Expr, QueryError> {
+        let mut items = vec![self.parse_unary()?];
+        while self.peek().is_some_and(|token| token.kind == TokenKind::Or) {
+            self.next();
+            items.push(self.parse_unary()?);
+        }
+
+        Ok(flatten(items, Expr::Or))
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, QueryError> {
+        let Some(token) = self.next() else {
+            return Err(self.error_here("expected a search term"));
+        };
+
+        match token.kind {
+            TokenKind::Not => {
+                self.enter(&token)?;
+                let expr = self.parse_unary()?;
+                self.leave();
+                Ok(Expr::Not(Box::new(expr)))
+            }
+            TokenKind::LParen => {
+                self.enter(&token)?;
+                let expr = self.parse_and()?;
+                self.leave();
+                match self.next() {
+                    Some(Token {
+                        kind: TokenKind::RParen,
+                        ..
+                    }) => Ok(expr),
+                    _ => Err(QueryError {
+                        message: "unclosed '('".to_string(),
+                        position: token.position,
+                    }),
+                }
+            }
+            TokenKind::Word(word) => {
+                // Orders are taken out of the query before it is parsed, so the ones left are
+                // inside a group or prefixed with `-` or `~`.
+                if word.starts_with("order:") {
+                    return Err(QueryError {
+                        message: "order: can't be grouped, negated or combined with ~".to_string(),
+                        position: token.position,
+                    });
+                }
+
+                if let Some(meta) = MetaTag::parse(&word).map_err(|message| QueryError {
+                    message,
+                    position: token.position,
+                })? {
+                    return Ok(Expr::Meta(meta));
+                }
+
+                let (name, kind) = match word.split_once(':') {
+                    Some((qualifier, name)) => match TagKind::parse(qualifier) {
+                        Some(kind) => (name.to_string(), Some(kind)),
+                        None => (word, None),
+                    },
+                    None => (word, None),
+                };
+
+                match (name.as_str(), kind) {
+                    ("", _) => Err(QueryError {
+                        message: "expected a tag after the qualifier".to_string(),
+                        position: token.position,
+                    }),
+                    ("*", Some(kind)) => Ok(Expr::Kind(kind)),
+                    (pattern, kind) if pattern.contains('*') => Ok(Expr::Wildcard {
+                        pattern: name,
+                        kind,
+                        position: token.position,
+                    }),
+                    _ => Ok(Expr::Tag { name, kind }),
+                }
+            }
+            TokenKind::OrPrefix | TokenKind::Or | TokenKind::RParen => {
+                self.index -= 1;
+                Err(self.error_here("expected a search term"))
+            }
+        }
+    }
+}
+
+fn flatten(mut items: Vec<Expr>, combine: fn(Vec<Expr>) -> Expr) -> Expr {
+    if items.len() == 1 {
+        items.remove(0)
+    } else {
+        combine(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(name: &str) -> Expr {
+        Expr::Tag {
+            name: name.to_string(),
+            kind: None,
+        }
+    }
+
+    fn not(expr: Expr) -> Expr {
+        Expr::Not(Box::new(expr))
+    }
+
+    fn error(input: &str) -> (String, usize) {
+        let err = parse(input).unwrap_err();
+        (err.message, err.position)
+    }
+
+    #[test]
+    fn parses_empty_query() {
+        assert_eq!(parse("").unwrap(), Expr::All);
+        assert_eq!(parse("  ").unwrap(), Expr::All);
+    }
+
+    #[test]
+    fn combines_terms() {
+        assert_eq!(parse("cat").unwrap(), tag("cat"));
+        assert_eq!(
+            parse("cat dog").unwrap(),
+            Expr::And(vec![tag("cat"), tag("dog")])
+        );
+        assert_eq!(
+            parse("cat ~ dog").unwrap(),
+            Expr::Or(vec![tag("cat"), tag("dog")])
+        );
+        assert_eq!(
+            parse("-cat dog").unwrap(),
+            Expr::And(vec![not(tag("cat")), tag("dog")])
+        );
+    }
+
+    #[test]
+    fn standalone_or_binds_tighter_than_and() {
+        assert_eq!(
+            parse("cat ~ fox -comic").unwrap(),
+            Expr::And(vec![
+                Expr::Or(vec![tag("cat"), tag("fox")]),
+                not(tag("comic"))
+            ])
+        );
+        assert_eq!(
+            parse("cat ~ fox ~ dog").unwrap(),
+            Expr::Or(vec![tag("cat"), tag("fox"), tag("dog")])
+        );
+        assert_eq!(
+            parse("-cat ~ dog").unwrap(),
+            Expr::Or(vec![not(tag("cat")), tag("dog")])
+        );
+    }
+
+    #[test]
+    fn prefixed_or_requires_the_other_terms() {
+        assert_eq!(
+            parse("~cat ~fox dog").unwrap(),
+            Expr::And(vec![tag("dog"), Expr::Or(vec![tag("cat"), tag("fox")])])
+        );
+        assert_eq!(
+            parse("cat ~ fox dog").unwrap(),
+            Expr::And(vec![Expr::Or(vec![tag("cat"), tag("fox")]), tag("dog")])
+        );
+    }
+
+    #[test]
+    fn parses_groups() {
+        assert_eq!(
+            parse("(cat dog) ~ fox").unwrap(),
+            Expr::Or(vec![Expr::And(vec![tag("cat"), tag("dog")]), tag("fox")])
+        );
+        assert_eq!(
+            parse("-(cat ~ dog) fox").unwrap(),
+            Expr::And(vec![
+                not(Expr::Or(vec![tag("cat"), tag("dog")])),
+                tag("fox")
+            ])
+        );
+        assert_eq!(parse("((cat))").unwrap(), tag("cat"));
+    }
+
+    #[test]
+    fn keeps_parentheses_inside_tags() {
+        assert_eq!(parse("saber_(fate)").unwrap(), tag("saber_(fate)"));
+        assert_eq!(
+            parse("(saber_(fate) ~ cat)").unwrap(),
+            Expr::Or(vec![tag("saber_(fate)"), tag("cat")])
+        );
+    }
+
+    #[test]
+    fn parses_qualified_tags() {
+        assert_eq!(
+            parse("artist:someone").unwrap(),
+            Expr::Tag {
+                name: "someone".to_string(),
+                kind: Some(TagKind::Artist)
+            }
+        );
+        assert_eq!(parse("artist:*").unwrap(), Expr::Kind(TagKind::Artist));
+        assert_eq!(
+            parse("cat*").unwrap(),
+            Expr::Wildcard {
+                pattern: "cat*".to_string(),
+                kind: None,
+                position: 0
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_unbalanced_parentheses() {
+        assert_eq!(error("(cat dog"), ("unclosed '('".to_string(), 0));
+        assert_eq!(error("cat dog)"), ("unmatched ')'".to_string(), 7));
+        assert_eq!(error("cat ()"), ("expected a search term".to_string(), 5));
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth| format!("{}cat{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(parse(&nested(MAX_DEPTH)).unwrap(), tag("cat"));
+        assert_eq!(
+            error(&nested(MAX_DEPTH + 1)),
+            ("query is nested too deeply".to_string(), MAX_DEPTH)
+        );
+
+        let negated = format!("{}cat", "-".repeat(MAX_DEPTH + 1));
+        assert_eq!(
+            error(&negated),
+            ("query is nested too deeply".to_string(), MAX_DEPTH)
+        );
+    }
+
+    #[test]
+    fn reports_byte_offsets_after_multibyte_tags() {
+        // `猫` takes three bytes.
+        assert_eq!(error("猫 (犬"), ("unclosed '('".to_string(), 4));
+        assert_eq!(error("猫 ~"), ("expected a search term".to_string(), 5));
+        assert_eq!(error("猫 犬)"), ("unmatched ')'".to_string(), 7));
+        assert_eq!(
+            error("猫 (order:id)"),
+            (
+                "order: can't be grouped, negated or combined with ~".to_string(),
+                5
+            )
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use anyhow::Result;
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::Database,
+    post::remove_post,
+    server::{AppResult, AppState, BadRequest},
+    site::{Site, SiteQuery},
+};
+
+/// Chunk of the favorites of a user. A session collects every chunk until it is finished, so the
+/// complete list doesn't have to be sent in one request.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconcileChunk {
+    post_ids: Vec<i64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkResponse {
+    /// Posts of this chunk that aren't downloaded yet.
+    missing: Vec<i64>,
+    /// Favorites received in this session so far.
+    received: i64,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FinishRequest {
+    /// Delete the local posts that are no longer favorited.
+    delete_unfavorited: bool,
+    /// Number of favorites the user has, required to delete so an incomplete session can't delete
+    /// posts that simply weren't sent.
+    expected_total: Option<i64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconcileReport {
+    /// Favorites that aren't downloaded.
+    missing: Vec<i64>,
+    /// Downloaded posts that aren't favorited anymore.
+    unfavorited: Vec<i64>,
+    summary: ReconcileSummary,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconcileSummary {
+    favorites: i64,
+    downloaded: i64,
+    missing: usize,
+    unfavorited: usize,
+    deleted: usize,
+}
+
+pub async fn add_reconcile_chunk(
+    State(AppState { database, .. }): State<AppState>,
+    Path(session): Path<String>,
+    Query(SiteQuery { site }): Query<SiteQuery>,
+    Json(ReconcileChunk { post_ids }): Json<ReconcileChunk>,
+) -> AppResult<Json<ChunkResponse>> {
+    database.add_reconcile_ids(&session, &post_ids).await?;
+    let downloaded = database
+        .filter_already_downloaded_posts(site, &post_ids)
+        .await?;
+
+    Ok(Json(ChunkResponse {
+        missing: post_ids
+            .into_iter()
+            .filter(|id| !downloaded.contains(id))
+            .collect(),
+        received: database.count_reconcile_ids(&session).await?,
+    }))
+}
+
+pub async fn get_reconcile_report(
+    State(AppState { database, .. }): State<AppState>,
+    Path(session): Path<String>,
+    Query(SiteQuery { site }): Query<SiteQuery>,
+) -> AppResult<Json<ReconcileReport>> {
+    Ok(Json(database.reconcile_report(&session, site).await?))
+}
+
+/// Reports the differences of the session and closes it, optionally deleting the unfavorited
+/// posts.
+pub async fn finish_reconcile(
+    State(AppState {
+        database,
+        base_path,
+        options,
+        ..
+    }): State<AppState>,
+    Path(session): Path<String>,
+    Query(SiteQuery { site }): Query<SiteQuery>,
+    request: Option<Json<FinishRequest>>,
+) -> AppResult<Json<ReconcileReport>> {
+    let Json(request) = request.unwrap_or_default();
+    let mut report = database.reconcile_report(&session, site).await?;
+
+    if request.delete_unfavorited {
+        if request.expected_total != Some(report.summary.favorites) {
+            return Err(BadRequest(format!(
+                "received {} favorites but expected {}, refusing to delete",
+                report.summary.favorites,
+                request
+                    .expected_total
+                    .map_or("no total".to_string(), |total| total.to_string())
+            ))
+            .into());
+        }
+
+        for &external_id in &report.unfavorited {
+            if let Some(post) = database.get_stored_post(site, external_id).await? {
+                remove_post(&database, &base_path, &options, post, site, external_id).await?;
+                report.summary.deleted += 1;
+            }
+        }
+    }
+
+    database.clear_reconcile_session(&session).await?;
+    Ok(Json(report))
+}
+
+impl Database {
+    async fn add_reconcile_ids(&self, session: &str, post_ids: &[i64]) -> Result<()> {
+        let mut trx = self.pool.begin().await?;
+        for post_id in post_ids {
+            sqlx::query!(
+                "INSERT OR IGNORE INTO reconcile_favorites (session, external_id) VALUES (?, ?)",
+                session,
+                post_id
+            )
+            .execute(&mut *trx)
+            .await?;
+        }
+        trx.commit().await?;
+        Ok(())
+    }
+
+    async fn count_reconcile_ids(&self, session: &str) -> Result<i64> {
+        Ok(sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM reconcile_favorites WHERE session = ?",
+            session
+        )
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    /// Compares the favorites of the session with the downloaded posts of `site`.
+    async fn reconcile_report(&self, session: &str, site: Site) -> Result<ReconcileReport> {
+        let site_name = site.as_str();
+        let missing = sqlx::query_scalar!(
+            r#"SELECT r.external_id
+            FROM reconcile_favorites r
+            LEFT JOIN posts p ON p.site = ? AND p.external_id = r.external_id
+            WHERE r.session = ? AND p.id IS NULL
+            ORDER BY r.external_id"#,
+            site_name,
+            session
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let unfavorited = sqlx::query_scalar!(
+            r#"SELECT p.external_id
+            FROM posts p
+            WHERE p.site = ? AND NOT EXISTS (
+                SELECT 1 FROM reconcile_favorites r
+                WHERE r.session = ? AND r.external_id = p.external_id
+            )
+            ORDER BY p.external_id"#,
+            site_name,
+            session
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(ReconcileReport {
+            summary: ReconcileSummary {
+                favorites: self.count_reconcile_ids(session).await?,
+                downloaded: self.get_download_count(site).await?,
+                missing: missing.len(),
+                unfavorited: unfavorited.len(),
+                deleted: 0,
+            },
+            missing,
+            unfavorited,
+        })
+    }
+
+    async fn clear_reconcile_session(&self, session: &str) -> Result<()> {
+        sqlx::query!("DELETE FROM reconcile_favorites WHERE session = ?", session)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
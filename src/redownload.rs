@@ -0,0 +1,110 @@
+use anyhow::Result;
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    database::Database,
+    json_ok,
+    server::{AppResult, AppState, NotFound},
+    site::{Site, SiteQuery},
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Redownload {
+    #[serde(rename = "id")]
+    external_id: i64,
+    reason: String,
+    flagged_at: NaiveDateTime,
+}
+
+#[derive(Deserialize)]
+pub struct FlagQuery {
+    reason: Option<String>,
+    #[serde(default)]
+    site: Site,
+}
+
+/// Lists the posts whose file is missing or broken, so they can be fetched again and re-uploaded.
+pub async fn get_redownloads(
+    State(AppState { database, .. }): State<AppState>,
+    Query(SiteQuery { site }): Query<SiteQuery>,
+) -> AppResult<Json<Value>> {
+    json_ok!({ "posts": database.get_redownloads(site).await? })
+}
+
+pub async fn flag_post(
+    State(AppState { database, .. }): State<AppState>,
+    Path(post_id): Path<i64>,
+    Query(FlagQuery { reason, site }): Query<FlagQuery>,
+) -> AppResult<Json<Value>> {
+    let Some(id) = database.get_post_id(site, post_id).await? else {
+        return Err(NotFound(format!("post {post_id} not found in database")).into());
+    };
+    database
+        .flag_redownload(id, reason.as_deref().unwrap_or("flagged manually"))
+        .await?;
+    json_ok!({ "ok": true })
+}
+
+impl Database {
+    async fn get_post_id(&self, site: Site, external_id: i64) -> Result<Option<i64>> {
+        let site = site.as_str();
+        Ok(sqlx::query_scalar!(
+            "SELECT id FROM posts WHERE site = ? AND external_id = ?",
+            site,
+            external_id
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn get_redownloads(&self, site: Site) -> Result<Vec<Redownload>> {
+        let site = site.as_str();
+        Ok(sqlx::query_as!(
+            Redownload,
+            r#"SELECT p.external_id, r.reason, r.flagged_at
+            FROM redownload r
+            JOIN posts p ON p.id = r.post_id
+            WHERE p.site = ?
+            ORDER BY r.flagged_at, p.id"#,
+            site
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    /// Flags a post for re-download, updating the reason if it already is.
+    pub async fn flag_redownload(&self, id: i64, reason: &str) -> Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO redownload (post_id, reason) VALUES (?, ?)
+            ON CONFLICT (post_id) DO UPDATE SET reason = excluded.reason"#,
+            id,
+            reason
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn is_flagged_for_redownload(&self, id: i64) -> Result<bool> {
+        Ok(sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM redownload WHERE post_id = ?) AS "flagged: bool""#,
+            id
+        )
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    pub async fn clear_redownload(&self, id: i64) -> Result<()> {
+        sqlx::query!("DELETE FROM redownload WHERE post_id = ?", id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -1,3 +1,9 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use anyhow::Result;
 use axum::{
     Json,
@@ -6,63 +12,267 @@ use axum::{
     http::{StatusCode, header},
     response::IntoResponse,
 };
+use camino::Utf8Path;
+use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sqlx::{Encode, QueryBuilder, Sqlite, Type};
 use tokio::fs::File;
 use tokio_util::io::ReaderStream;
+use tracing::warn;
 
 use crate::{
     database::Database,
     json_ok,
-    media_processor::{file_name, mini_thumb},
-    server::{AppResult, AppState},
-    upload::PostIdsResponse,
+    media_processor::{file_name, file_type, mini_thumb},
+    metatag::{Comparison, MediaType, MetaTag, Order, OrderKey},
+    query::{self, Expr, QueryError},
+    server::{AppResult, AppState, BadRequest},
+    site::{Site, SiteQuery},
+    upload::{Tag, TagKind},
 };
 
-pub struct Search<'a> {
-    include: Vec<&'a str>,
-    exclude: Vec<&'a str>,
+const MAX_WILDCARD_TAGS: i64 = 500;
+const RANDOM_SEED_MODULUS: i64 = 1 << 31;
+const DEFAULT_POSTS_LIMIT: i64 = 100;
+const MAX_POSTS_LIMIT: i64 = 1000;
+
+pub struct Search {
+    /// Only posts of this site are searched, their ids would be ambiguous otherwise.
+    site: Site,
+    query: Expr,
+    order: Order,
+    seed: i64,
 }
 
-impl<'a> Search<'a> {
-    fn new(input: &'a str) -> Self {
-        let mut result = Self {
-            include: Vec::new(),
-            exclude: Vec::new(),
-        };
+impl Search {
+    fn new(input: &str, seed: Option<i64>, site: Site) -> Result<Self, QueryError> {
+        // `order:` terms are not filters, so they are taken out before parsing. They are replaced
+        // with spaces to keep the positions in parse errors intact.
+        let mut order = None;
+        let mut filter = input.to_string();
+        for (position, word) in query::split_words(input) {
+            let Some(value) = word.strip_prefix("order:") else {
+                continue;
+            };
+            if order.is_some() {
+                return Err(QueryError {
+                    message: "only one order can be used".to_string(),
+                    position,
+                });
+            }
+            order = Some(Order::parse(value).ok_or_else(|| QueryError {
+                message: format!("unknown order '{value}'"),
+                position,
+            })?);
+            filter.replace_range(position..position + word.len(), &" ".repeat(word.len()));
+        }
 
-        for term in input.split_whitespace() {
-            if let Some(t) = term.strip_prefix("-") {
-                if !t.is_empty() {
-                    result.exclude.push(t)
-                }
-            } else {
-                result.include.push(term);
+        Ok(Self {
+            site,
+            query: query::parse(&filter)?,
+            order: order.unwrap_or_default(),
+            seed: seed
+                .unwrap_or_else(random_seed)
+                .rem_euclid(RANDOM_SEED_MODULUS),
+        })
+    }
+
+    /// SQL expression the results are ordered by. Ties are broken by `p.id`.
+    fn sort_key(&self) -> String {
+        match self.order.key {
+            OrderKey::Added => "p.id".to_string(),
+            OrderKey::Id => "p.external_id".to_string(),
+            OrderKey::FileSize => "COALESCE(p.file_size, 0)".to_string(),
+            OrderKey::Score => "COALESCE(p.score, 0)".to_string(),
+            OrderKey::TagCount => {
+                "(SELECT COUNT(1) FROM post_tags pt WHERE pt.post_id = p.id)".to_string()
             }
+            // SQLite's random() can't be seeded, so posts are shuffled with a multiplicative hash.
+            OrderKey::Random => format!("(((p.id + {}) * 2654435761) % 4294967291)", self.seed),
         }
+    }
+}
+
+struct SearchHit {
+    id: i64,
+    external_id: i64,
+}
+
+/// Position in the search results after which the next page starts.
+struct Cursor {
+    sort_key: i64,
+    id: i64,
+}
 
-        result
+impl Cursor {
+    fn parse(value: &str) -> Result<Self, BadRequest> {
+        value
+            .split_once('_')
+            .and_then(|(sort_key, id)| {
+                Some(Self {
+                    sort_key: sort_key.parse().ok()?,
+                    id: id.parse().ok()?,
+                })
+            })
+            .ok_or_else(|| BadRequest(format!("invalid cursor '{value}'")))
     }
 }
 
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.sort_key, self.id)
+    }
+}
+
+fn random_seed() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.subsec_nanos() as i64)
+}
+
 #[derive(Deserialize)]
 pub struct SearchQuery {
     term: String,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    seed: Option<i64>,
+    #[serde(default)]
+    site: Site,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResponse {
+    post_ids: Vec<i64>,
+    total: i64,
+    next_cursor: Option<String>,
+    seed: i64,
+}
+
+#[derive(Deserialize)]
+pub struct PostsQuery {
+    #[serde(default)]
+    term: String,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    seed: Option<i64>,
+    #[serde(default)]
+    site: Site,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostsResponse {
+    posts: Vec<PostListing>,
+    total: i64,
+    next_cursor: Option<String>,
+    seed: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostListing {
+    #[serde(rename = "id")]
+    external_id: i64,
+    mime: String,
+    extension: String,
+    added_at: NaiveDateTime,
+    original: bool,
+    width: Option<i64>,
+    height: Option<i64>,
+    duration: Option<f64>,
+    rating: Option<String>,
+    score: Option<i64>,
+    source: Option<String>,
+    parent_id: Option<i64>,
+    tags: Vec<Tag>,
+}
+
+#[derive(sqlx::FromRow)]
+struct ListingRow {
+    id: i64,
+    external_id: i64,
+    mime: String,
+    extension: String,
+    added_at: NaiveDateTime,
+    original: bool,
+    width: Option<i64>,
+    height: Option<i64>,
+    duration: Option<f64>,
+    rating: Option<String>,
+    score: Option<i64>,
+    source: Option<String>,
+    parent_id: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct AutocompleteQuery {
+    term: String,
+    kind: Option<TagKind>,
 }
+
 pub async fn search(
     State(AppState { database, .. }): State<AppState>,
-    Query(SearchQuery { term }): Query<SearchQuery>,
-) -> AppResult<Json<PostIdsResponse>> {
-    let search = Search::new(&term);
-    let post_ids = database.search(&search).await?;
-    Ok(Json(PostIdsResponse { post_ids }))
+    Query(SearchQuery {
+        term,
+        limit,
+        cursor,
+        seed,
+        site,
+    }): Query<SearchQuery>,
+) -> AppResult<Json<SearchResponse>> {
+    if limit.is_some_and(|limit| limit < 1) {
+        return Err(BadRequest("limit has to be at least 1".to_string()).into());
+    }
+    let cursor = cursor.as_deref().map(Cursor::parse).transpose()?;
+
+    let mut search = Search::new(&term, seed, site)?;
+    let (hits, next_cursor) = database.search(&mut search, limit, cursor).await?;
+    let total = database.search_count(&search).await?;
+
+    Ok(Json(SearchResponse {
+        post_ids: hits.iter().map(|hit| hit.external_id).collect(),
+        total,
+        next_cursor: next_cursor.map(|cursor| cursor.to_string()),
+        seed: search.seed,
+    }))
+}
+
+pub async fn list_posts(
+    State(AppState { database, .. }): State<AppState>,
+    Query(PostsQuery {
+        term,
+        limit,
+        cursor,
+        seed,
+        site,
+    }): Query<PostsQuery>,
+) -> AppResult<Json<PostsResponse>> {
+    let limit = limit.unwrap_or(DEFAULT_POSTS_LIMIT);
+    if !(1..=MAX_POSTS_LIMIT).contains(&limit) {
+        return Err(BadRequest(format!("limit has to be between 1 and {MAX_POSTS_LIMIT}")).into());
+    }
+    let cursor = cursor.as_deref().map(Cursor::parse).transpose()?;
+
+    let mut search = Search::new(&term, seed, site)?;
+    let (hits, next_cursor) = database.search(&mut search, Some(limit), cursor).await?;
+    let total = database.search_count(&search).await?;
+    let ids: Vec<i64> = hits.iter().map(|hit| hit.id).collect();
+
+    Ok(Json(PostsResponse {
+        posts: database.list_posts(&ids).await?,
+        total,
+        next_cursor: next_cursor.map(|cursor| cursor.to_string()),
+        seed: search.seed,
+    }))
 }
 
 pub async fn autocomplete(
     State(AppState { database, .. }): State<AppState>,
-    Query(SearchQuery { term }): Query<SearchQuery>,
+    Query(AutocompleteQuery { term, kind }): Query<AutocompleteQuery>,
 ) -> AppResult<Json<Value>> {
-    json_ok!({"suggestions": database.autocomplete(&term).await?})
+    json_ok!({"suggestions": database.autocomplete(&term, kind).await?})
 }
 
 pub async fn serve_image(
@@ -72,16 +282,18 @@ pub async fn serve_image(
         ..
     }): State<AppState>,
     Path(post_id): Path<i64>,
+    Query(SiteQuery { site }): Query<SiteQuery>,
 ) -> impl IntoResponse {
-    let (path, mime) = match database.get_post(post_id).await {
+    let (path, mime) = match database.get_post(site, post_id).await {
         Ok(post) => {
             let name = file_name(post.id, post.external_id, &post.extension);
             let path = if post.mime.starts_with("image") {
-                base_path.join(name)
+                base_path.join(&name)
             } else {
                 base_path.join(".thumbs").join(format!("{name}.jpeg"))
             };
             if !path.is_file() {
+                flag_missing_file(&database, &base_path, post.id, &name).await;
                 return Err((StatusCode::NOT_FOUND, "file not found on disk"));
             }
             (path, post.mime)
@@ -107,8 +319,9 @@ pub async fn serve_mini(
         ..
     }): State<AppState>,
     Path(post_id): Path<i64>,
+    Query(SiteQuery { site }): Query<SiteQuery>,
 ) -> impl IntoResponse {
-    let (original_path, name) = match database.get_post(post_id).await {
+    let (id, original_path, name) = match database.get_post(site, post_id).await {
         Ok(post) => {
             let name = file_name(post.id, post.external_id, &post.extension);
             let path = if post.mime.starts_with("image") {
@@ -117,16 +330,24 @@ pub async fn serve_mini(
                 base_path.join(".thumbs").join(format!("{name}.jpeg"))
             };
             if !path.is_file() {
+                flag_missing_file(&database, &base_path, post.id, &name).await;
                 return Err((StatusCode::NOT_FOUND, "file not found on disk"));
             }
-            (path, name)
+            (post.id, path, name)
         }
         Err(_) => return Err((StatusCode::NOT_FOUND, "post not found in database")),
     };
 
     let path = match mini_thumb(&name, &original_path, &base_path).await {
         Ok(path) => path,
-        Err(_) => {
+        Err(err) => {
+            // Only blame the file if it isn't recognizable anymore, vips might just be missing.
+            if file_type(original_path.as_std_path()).await.is_err() {
+                warn!("{name} could not be decoded, flagging it for re-download: {err:#}");
+                if let Err(err) = database.flag_redownload(id, "corrupt file").await {
+                    warn!("Could not flag {name} for re-download: {err:#}");
+                }
+            }
             return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "unable to create mini thumb",
@@ -145,6 +366,17 @@ pub async fn serve_mini(
     Ok(([(header::CONTENT_TYPE, "image/jpeg")], body))
 }
 
+/// Flags the post for re-download if its original file is gone, the thumbnail of videos can be
+/// regenerated by the check command instead.
+async fn flag_missing_file(database: &Database, base_path: &Utf8Path, id: i64, name: &str) {
+    if base_path.join(name).is_file() {
+        return;
+    }
+    if let Err(err) = database.flag_redownload(id, "missing file").await {
+        warn!("Could not flag {name} for re-download: {err:#}");
+    }
+}
+
 struct PostData {
     id: i64,
     external_id: i64,
@@ -160,54 +392,387 @@ struct AutoCompleteSuggestion {
 }
 
 impl Database {
-    async fn search(&self, search: &Search<'_>) -> Result<Vec<i64>> {
-        let mut query_builder = sqlx::QueryBuilder::new(
-            r#"SELECT p.external_id 
-            FROM posts p
-            JOIN post_tags pt ON p.id = pt.post_id
-            JOIN tags t ON t.id = pt.tag_id
-            WHERE t.name IN "#,
+    /// Returns one page of matching posts and the cursor for the next page. Without a limit all
+    /// results are returned at once.
+    async fn search(
+        &self,
+        search: &mut Search,
+        limit: Option<i64>,
+        cursor: Option<Cursor>,
+    ) -> Result<(Vec<SearchHit>, Option<Cursor>)> {
+        self.expand_wildcards(&mut search.query).await?;
+
+        let sort_key = search.sort_key();
+        let direction = if search.order.ascending {
+            "ASC"
+        } else {
+            "DESC"
+        };
+        let comparison = if search.order.ascending { ">" } else { "<" };
+
+        let mut query_builder = QueryBuilder::new("SELECT p.external_id, ");
+        query_builder.push(&sort_key);
+        query_builder.push(" AS sort_key, p.id FROM posts p WHERE p.site = ");
+        query_builder.push_bind(search.site.as_str());
+        query_builder.push(" AND (");
+        push_expr(&mut query_builder, &search.query);
+        query_builder.push(")");
+
+        if let Some(cursor) = cursor {
+            query_builder.push(format!(" AND ({sort_key} {comparison} "));
+            query_builder.push_bind(cursor.sort_key);
+            query_builder.push(format!(" OR ({sort_key} = "));
+            query_builder.push_bind(cursor.sort_key);
+            query_builder.push(format!(" AND p.id {comparison} "));
+            query_builder.push_bind(cursor.id);
+            query_builder.push("))");
+        }
+
+        query_builder.push(format!(" ORDER BY sort_key {direction}, p.id {direction}"));
+        if let Some(limit) = limit {
+            query_builder.push(" LIMIT ");
+            query_builder.push_bind(limit);
+        }
+
+        let rows: Vec<(i64, i64, i64)> =
+            query_builder.build_query_as().fetch_all(&self.pool).await?;
+
+        let next_cursor = match (limit, rows.last()) {
+            (Some(limit), Some(&(_, sort_key, id))) if rows.len() as i64 == limit => {
+                Some(Cursor { sort_key, id })
+            }
+            _ => None,
+        };
+
+        Ok((
+            rows.into_iter()
+                .map(|(external_id, _, id)| SearchHit { id, external_id })
+                .collect(),
+            next_cursor,
+        ))
+    }
+
+    /// Counts all results of an already expanded search.
+    async fn search_count(&self, search: &Search) -> Result<i64> {
+        let mut query_builder = QueryBuilder::new("SELECT COUNT(1) FROM posts p WHERE p.site = ");
+        query_builder.push_bind(search.site.as_str());
+        query_builder.push(" AND (");
+        push_expr(&mut query_builder, &search.query);
+        query_builder.push(")");
+
+        Ok(query_builder
+            .build_query_scalar()
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    /// Loads the listing data of the given posts, keeping their order.
+    async fn list_posts(&self, ids: &[i64]) -> Result<Vec<PostListing>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut query_builder = QueryBuilder::new(
+            r#"SELECT id, external_id, mime, extension, added_at, original, width, height, duration,
+                rating, score, source, parent_id
+            FROM posts
+            WHERE id IN "#,
         );
-        query_builder.push_tuples(&search.include, |mut builder, term| {
-            builder.push_bind(term);
+        query_builder.push_tuples(ids, |mut builder, id| {
+            builder.push_bind(*id);
         });
-        query_builder.push(
-            r#" GROUP BY p.external_id
-            HAVING COUNT(1) = "#,
+        let rows: Vec<ListingRow> = query_builder.build_query_as().fetch_all(&self.pool).await?;
+
+        let mut query_builder = QueryBuilder::new(
+            r#"SELECT pt.post_id, t.name, t.kind
+            FROM post_tags pt
+            JOIN tags t ON t.id = pt.tag_id
+            WHERE pt.post_id IN "#,
         );
-        query_builder.push_bind(search.include.len() as i64);
-        query_builder.push(" ORDER BY p.id DESC");
+        query_builder.push_tuples(ids, |mut builder, id| {
+            builder.push_bind(*id);
+        });
+        query_builder.push(" ORDER BY t.name");
+        let tag_rows: Vec<(i64, String, String)> =
+            query_builder.build_query_as().fetch_all(&self.pool).await?;
 
-        Ok(query_builder
-            .build_query_scalar()
+        let mut tags: HashMap<i64, Vec<Tag>> = HashMap::new();
+        for (post_id, name, kind) in tag_rows {
+            let Some(kind) = TagKind::parse(&kind) else {
+                continue;
+            };
+            tags.entry(post_id).or_default().push(Tag { name, kind });
+        }
+
+        let mut posts: HashMap<i64, PostListing> = rows
+            .into_iter()
+            .map(|row| {
+                let listing = PostListing {
+                    external_id: row.external_id,
+                    mime: row.mime,
+                    extension: row.extension,
+                    added_at: row.added_at,
+                    original: row.original,
+                    width: row.width,
+                    height: row.height,
+                    duration: row.duration,
+                    rating: row.rating,
+                    score: row.score,
+                    source: row.source,
+                    parent_id: row.parent_id,
+                    tags: tags.remove(&row.id).unwrap_or_default(),
+                };
+                (row.id, listing)
+            })
+            .collect();
+
+        Ok(ids.iter().filter_map(|id| posts.remove(id)).collect())
+    }
+
+    async fn expand_wildcards(&self, query: &mut Expr) -> Result<()> {
+        for wildcard in query.wildcards_mut() {
+            let Expr::Wildcard {
+                pattern,
+                kind,
+                position,
+            } = wildcard
+            else {
+                continue;
+            };
+
+            let like = like_pattern(pattern);
+            let kind = kind.map(|kind| kind.as_str());
+            let limit = MAX_WILDCARD_TAGS + 1;
+            let tag_ids = sqlx::query_scalar!(
+                r#"SELECT id FROM tags
+                WHERE name LIKE ? ESCAPE '\' AND (? IS NULL OR kind = ?)
+                LIMIT ?"#,
+                like,
+                kind,
+                kind,
+                limit
+            )
             .fetch_all(&self.pool)
-            .await?)
+            .await?;
+
+            if tag_ids.len() > MAX_WILDCARD_TAGS as usize {
+                return Err(QueryError {
+                    message: format!("'{pattern}' matches more than {MAX_WILDCARD_TAGS} tags"),
+                    position: *position,
+                }
+                .into());
+            }
+
+            *wildcard = Expr::TagIds(tag_ids);
+        }
+
+        Ok(())
     }
 
-    async fn autocomplete(&self, term: &str) -> Result<Vec<AutoCompleteSuggestion>> {
+    async fn autocomplete(
+        &self,
+        term: &str,
+        kind: Option<TagKind>,
+    ) -> Result<Vec<AutoCompleteSuggestion>> {
         let like = format!("%{term}%");
+        let kind = kind.map(|kind| kind.as_str());
         Ok(sqlx::query_as!(
             AutoCompleteSuggestion,
             r#"SELECT name, kind, uses
             FROM tags_with_uses
-            WHERE name LIKE ?
+            WHERE name LIKE ? AND (? IS NULL OR kind = ?)
             LIMIT 10"#,
-            like
+            like,
+            kind,
+            kind
         )
         .fetch_all(&self.pool)
         .await?)
     }
 
-    async fn get_post(&self, external_id: i64) -> Result<PostData> {
+    async fn get_post(&self, site: Site, external_id: i64) -> Result<PostData> {
+        let site = site.as_str();
         Ok(sqlx::query_as!(
             PostData,
             r#"SELECT id, external_id, extension, mime
             FROM posts
-            WHERE external_id = ?
+            WHERE site = ? AND external_id = ?
             "#,
+            site,
             external_id
         )
         .fetch_one(&self.pool)
         .await?)
     }
 }
+
+fn push_expr(query_builder: &mut QueryBuilder<'_, Sqlite>, expr: &Expr) {
+    match expr {
+        Expr::All => {
+            query_builder.push("1");
+        }
+        Expr::Tag { name, kind } => {
+            query_builder.push(
+                r#"EXISTS (
+                SELECT 1 FROM post_tags pt
+                JOIN tags t ON t.id = pt.tag_id
+                WHERE pt.post_id = p.id AND t.name = "#,
+            );
+            query_builder.push_bind(name.clone());
+            if let Some(kind) = kind {
+                query_builder.push(" AND t.kind = ");
+                query_builder.push_bind(kind.as_str());
+            }
+            query_builder.push(")");
+        }
+        Expr::Kind(kind) => {
+            query_builder.push(
+                r#"EXISTS (
+                SELECT 1 FROM post_tags pt
+                JOIN tags t ON t.id = pt.tag_id
+                WHERE pt.post_id = p.id AND t.kind = "#,
+            );
+            query_builder.push_bind(kind.as_str());
+            query_builder.push(")");
+        }
+        Expr::TagIds(tag_ids) if tag_ids.is_empty() => {
+            query_builder.push("0");
+        }
+        Expr::TagIds(tag_ids) => {
+            query_builder.push(
+                r#"EXISTS (
+                SELECT 1 FROM post_tags pt
+                WHERE pt.post_id = p.id AND pt.tag_id IN "#,
+            );
+            query_builder.push_tuples(tag_ids, |mut builder, tag_id| {
+                builder.push_bind(*tag_id);
+            });
+            query_builder.push(")");
+        }
+        Expr::Wildcard { .. } => unreachable!("wildcards are expanded before building the query"),
+        Expr::Meta(meta) => push_meta(query_builder, meta),
+        Expr::Not(expr) => {
+            query_builder.push("NOT ");
+            push_expr(query_builder, expr);
+        }
+        Expr::And(exprs) => push_joined(query_builder, exprs, " AND "),
+        Expr::Or(exprs) => push_joined(query_builder, exprs, " OR "),
+    }
+}
+
+fn push_meta(query_builder: &mut QueryBuilder<'_, Sqlite>, meta: &MetaTag) {
+    match meta {
+        MetaTag::Type(MediaType::Image) => {
+            query_builder.push("p.mime LIKE 'image/%'");
+        }
+        MetaTag::Type(MediaType::Video) => {
+            query_builder.push("p.mime LIKE 'video/%'");
+        }
+        MetaTag::Type(MediaType::Gif) => {
+            query_builder.push("p.mime = 'image/gif'");
+        }
+        MetaTag::Mime(pattern) => {
+            query_builder.push("p.mime LIKE ");
+            query_builder.push_bind(like_pattern(pattern));
+            query_builder.push(r#" ESCAPE '\'"#);
+        }
+        MetaTag::Original(original) => {
+            query_builder.push("p.original = ");
+            query_builder.push_bind(*original);
+        }
+        MetaTag::Added(comparison) => {
+            push_comparison(query_builder, "date(p.added_at)", comparison)
+        }
+        MetaTag::Id(comparison) => push_comparison(query_builder, "p.external_id", comparison),
+        MetaTag::Width(comparison) => push_comparison(query_builder, "p.width", comparison),
+        MetaTag::Height(comparison) => push_comparison(query_builder, "p.height", comparison),
+        MetaTag::Duration(comparison) => push_comparison(query_builder, "p.duration", comparison),
+        MetaTag::FileSize(comparison) => push_comparison(query_builder, "p.file_size", comparison),
+        MetaTag::Audio(has_audio) => {
+            query_builder.push("p.has_audio = ");
+            query_builder.push_bind(*has_audio);
+        }
+        MetaTag::Rating(rating) => {
+            query_builder.push("p.rating = ");
+            query_builder.push_bind(rating.as_str());
+        }
+        MetaTag::Score(comparison) => push_comparison(query_builder, "p.score", comparison),
+        MetaTag::Source(pattern) => {
+            query_builder.push("p.source LIKE ");
+            query_builder.push_bind(like_pattern(pattern));
+            query_builder.push(r#" ESCAPE '\'"#);
+        }
+        MetaTag::TagCount(comparison) => push_comparison(
+            query_builder,
+            "(SELECT COUNT(1) FROM post_tags pt WHERE pt.post_id = p.id)",
+            comparison,
+        ),
+    }
+}
+
+fn push_comparison<'args, T>(
+    query_builder: &mut QueryBuilder<'args, Sqlite>,
+    column: &str,
+    comparison: &Comparison<T>,
+) where
+    T: 'args + Clone + Encode<'args, Sqlite> + Type<Sqlite>,
+{
+    query_builder.push(column);
+    let value = match comparison {
+        Comparison::Eq(value) => {
+            query_builder.push(" = ");
+            value
+        }
+        Comparison::Lt(value) => {
+            query_builder.push(" < ");
+            value
+        }
+        Comparison::Le(value) => {
+            query_builder.push(" <= ");
+            value
+        }
+        Comparison::Gt(value) => {
+            query_builder.push(" > ");
+            value
+        }
+        Comparison::Ge(value) => {
+            query_builder.push(" >= ");
+            value
+        }
+        Comparison::Range(from, to) => {
+            query_builder.push(" BETWEEN ");
+            query_builder.push_bind(from.clone());
+            query_builder.push(" AND ");
+            to
+        }
+    };
+    query_builder.push_bind(value.clone());
+}
+
+/// Turns a `*` wildcard pattern into a `LIKE` pattern, escaping everything else that `LIKE` would
+/// treat specially. Tags are full of underscores.
+fn like_pattern(pattern: &str) -> String {
+    let mut like = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        match c {
+            '*' => like.push('%'),
+            '%' | '_' | '\\' => {
+                like.push('\\');
+                like.push(c);
+            }
+            c => like.push(c),
+        }
+    }
+    like
+}
+
+fn push_joined(query_builder: &mut QueryBuilder<'_, Sqlite>, exprs: &[Expr], separator: &str) {
+    query_builder.push("(");
+    for (index, expr) in exprs.iter().enumerate() {
+        if index > 0 {
+            query_builder.push(separator);
+        }
+        push_expr(query_builder, expr);
+    }
+    query_builder.push(")");
+}
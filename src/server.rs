@@ -1,8 +1,8 @@
-use std::time::Duration;
+use std::{fmt, time::Duration};
 
 use anyhow::Result;
 use axum::{
-    Router,
+    Json, Router,
     extract::DefaultBodyLimit,
     http::{HeaderValue, Method, StatusCode, header},
     response::IntoResponse,
@@ -13,12 +13,28 @@ use axum::{
 use camino::{Utf8Path, Utf8PathBuf};
 use tokio::{net::TcpListener, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
-use tower_http::{cors::CorsLayer, trace::TraceLayer};
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    trace::TraceLayer,
+};
 use tracing::error;
 
 use crate::{
     database::Database,
-    search::{autocomplete, search, serve_image, serve_mini},
+    duplicates::{get_duplicates, get_similar_clusters, get_similar_posts},
+    events::{Events, stream_events},
+    fetcher::{fetch_posts, get_post_metadata},
+    jobs::{JobQueue, get_job, list_jobs},
+    post::{delete_post, get_post, get_post_history},
+    query::QueryError,
+    reconcile::{add_reconcile_chunk, finish_reconcile, get_reconcile_report},
+    redownload::{flag_post, get_redownloads},
+    search::{autocomplete, list_posts, search, serve_image, serve_mini},
+    site::Site,
+    sync::{
+        checkpoint_sync, finish_sync, get_resumable_sync, get_sync_history, get_sync_session,
+        report_sync_error, start_sync,
+    },
     upload::{check_download_status, get_download_count, upload},
 };
 
@@ -40,23 +56,78 @@ macro_rules! html_ok {
 pub struct AppState {
     pub database: Database,
     pub base_path: Utf8PathBuf,
+    pub options: ServerOptions,
+    pub jobs: JobQueue,
+    pub events: Events,
+    /// Cancelled when the server shuts down, which long running responses have to stop for.
+    pub shutdown_token: CancellationToken,
 }
 
-pub fn create_router(database: &Database, base_path: &Utf8Path) -> Router {
+#[derive(Clone)]
+pub struct ServerOptions {
+    /// Hard link uploads whose content is already stored instead of saving a second copy.
+    pub dedupe: bool,
+    /// Keep the files of deleted posts in `.trash` for this many days instead of removing them.
+    pub trash_days: Option<u64>,
+    /// Site the server downloads posts from itself, `None` if only uploads are accepted.
+    pub fetch_base_url: Option<String>,
+    /// `user_id` and `api_key` for the API of that site.
+    pub api_credentials: Option<(String, String)>,
+}
+
+pub fn create_router(
+    database: &Database,
+    base_path: &Utf8Path,
+    options: ServerOptions,
+    jobs: &JobQueue,
+    events: &Events,
+    shutdown_token: &CancellationToken,
+) -> Router {
     Router::new()
         .route("/upload", post(upload))
         .layer(DefaultBodyLimit::max(1024 * 1024 * 1024))
+        .route("/events", get(stream_events))
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/{job_id}", get(get_job))
+        .route("/fetch", post(fetch_posts))
+        .route("/fetch/{post_id}", get(get_post_metadata))
         .route("/check", post(check_download_status))
         .route("/count", get(get_download_count))
+        .route(
+            "/reconcile/{session}",
+            get(get_reconcile_report).post(add_reconcile_chunk),
+        )
+        .route("/reconcile/{session}/finish", post(finish_reconcile))
         .route("/search", get(search))
         .route("/search/autocomplete", get(autocomplete))
+        .route("/posts", get(list_posts))
+        .route("/post/{post_id}", get(get_post).delete(delete_post))
+        .route("/post/{post_id}/history", get(get_post_history))
+        .route("/post/{post_id}/similar", get(get_similar_posts))
+        .route("/sync", get(get_sync_history).post(start_sync))
+        .route("/sync/resumable", get(get_resumable_sync))
+        .route("/sync/{session_id}", get(get_sync_session))
+        .route("/sync/{session_id}/checkpoint", post(checkpoint_sync))
+        .route("/sync/{session_id}/error", post(report_sync_error))
+        .route("/sync/{session_id}/finish", post(finish_sync))
+        .route("/redownload", get(get_redownloads))
+        .route("/redownload/{post_id}", post(flag_post))
+        .route("/duplicates", get(get_duplicates))
+        .route("/duplicates/similar", get(get_similar_clusters))
         .route("/image/{post_id}", get(serve_image))
         .route("/image/mini/{post_id}", get(serve_mini))
         .route("/arueshalae.user.js", get(send_userscript))
         .layer(
             CorsLayer::new()
-                .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-                .allow_origin("https://rule34.xxx".parse::<HeaderValue>().unwrap())
+                .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS])
+                // The userscript runs on these
+                .allow_origin(AllowOrigin::list(
+                    Site::ALL
+                        .iter()
+                        .filter(|site| site.is_gelbooru())
+                        .flat_map(|site| site.origins())
+                        .map(|origin| HeaderValue::from_static(origin)),
+                ))
                 .allow_headers([header::CONTENT_TYPE])
                 .max_age(Duration::from_secs(60 * 60 * 2)),
         )
@@ -64,6 +135,10 @@ pub fn create_router(database: &Database, base_path: &Utf8Path) -> Router {
         .with_state(AppState {
             database: database.clone(),
             base_path: base_path.to_path_buf(),
+            options,
+            jobs: jobs.clone(),
+            events: events.clone(),
+            shutdown_token: shutdown_token.clone(),
         })
 }
 
@@ -95,6 +170,16 @@ pub struct AppError(anyhow::Error);
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        if let Some(err) = self.0.downcast_ref::<QueryError>() {
+            return (StatusCode::BAD_REQUEST, Json(err)).into_response();
+        }
+        if let Some(BadRequest(message)) = self.0.downcast_ref::<BadRequest>() {
+            return (StatusCode::BAD_REQUEST, message.clone()).into_response();
+        }
+        if let Some(NotFound(message)) = self.0.downcast_ref::<NotFound>() {
+            return (StatusCode::NOT_FOUND, message.clone()).into_response();
+        }
+
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Something went wrong: {}", self.0),
@@ -113,3 +198,26 @@ where
 }
 
 pub type AppResult<T> = Result<T, AppError>;
+
+/// Error caused by invalid input from the client, answered with a 400 instead of a 500.
+#[derive(Debug)]
+pub struct BadRequest(pub String);
+
+impl fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for BadRequest {}
+
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
@@ -0,0 +1,89 @@
+//! Sites posts are downloaded from. Post ids are only unique within a site, so every request that
+//! refers to posts by their id takes a `site` parameter, which defaults to rule34.xxx.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Site {
+    #[default]
+    Rule34,
+    Gelbooru,
+    Safebooru,
+    Danbooru,
+}
+
+#[derive(Deserialize)]
+pub struct SiteQuery {
+    #[serde(default)]
+    pub site: Site,
+}
+
+impl Site {
+    pub const ALL: [Site; 4] = [
+        Self::Rule34,
+        Self::Gelbooru,
+        Self::Safebooru,
+        Self::Danbooru,
+    ];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "rule34" => Some(Self::Rule34),
+            "gelbooru" => Some(Self::Gelbooru),
+            "safebooru" => Some(Self::Safebooru),
+            "danbooru" => Some(Self::Danbooru),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Rule34 => "rule34",
+            Self::Gelbooru => "gelbooru",
+            Self::Safebooru => "safebooru",
+            Self::Danbooru => "danbooru",
+        }
+    }
+
+    /// Origins the site is served from.
+    pub fn origins(&self) -> &'static [&'static str] {
+        match self {
+            Self::Rule34 => &["https://rule34.xxx"],
+            Self::Gelbooru => &["https://gelbooru.com"],
+            Self::Safebooru => &["https://safebooru.org"],
+            Self::Danbooru => &["https://danbooru.donmai.us", "https://safebooru.donmai.us"],
+        }
+    }
+
+    /// Whether the site runs Gelbooru 0.2 like rule34.xxx does. Only the pages and API of those are
+    /// understood by the userscript and the fetcher, other sites can only be uploaded from.
+    pub fn is_gelbooru(&self) -> bool {
+        !matches!(self, Self::Danbooru)
+    }
+
+    /// Link back to the post on the site.
+    pub fn post_url(&self, id: i64) -> String {
+        match self {
+            Self::Rule34 | Self::Gelbooru | Self::Safebooru => {
+                format!("{}/index.php?page=post&s=view&id={id}", self.origins()[0])
+            }
+            Self::Danbooru => format!("{}/posts/{id}", self.origins()[0]),
+        }
+    }
+
+    /// The site a URL belongs to, e.g. the one given to `--fetch-from`.
+    pub fn from_url(url: &str) -> Option<Self> {
+        let host = url
+            .split_once("://")
+            .map_or(url, |(_, rest)| rest)
+            .split(['/', ':'])
+            .next()?;
+        Self::ALL.into_iter().find(|site| {
+            site.origins().iter().any(|origin| {
+                let origin_host = origin.trim_start_matches("https://");
+                host == origin_host || host.strip_prefix("www.") == Some(origin_host)
+            })
+        })
+    }
+}
@@ -0,0 +1,320 @@
+use anyhow::Result;
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    database::Database,
+    events::ProgressEvent,
+    json_ok,
+    server::{AppResult, AppState, BadRequest, NotFound},
+    site::Site,
+};
+
+const HISTORY_LIMIT: i64 = 50;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncSession {
+    id: i64,
+    site: String,
+    kind: String,
+    user_id: i64,
+    goal: i64,
+    pid: Option<i64>,
+    downloaded: i64,
+    errors: i64,
+    started_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+    finished_at: Option<NaiveDateTime>,
+    /// Seconds from the start until the session was finished, or until the last checkpoint if it
+    /// wasn't.
+    duration: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncError {
+    post_id: Option<i64>,
+    error: String,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StartSync {
+    /// `sync` for new favorites only, `full` to go through every favorite.
+    kind: String,
+    user_id: i64,
+    goal: i64,
+    #[serde(default)]
+    site: Site,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Checkpoint {
+    pid: i64,
+    downloaded: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportError {
+    post_id: Option<i64>,
+    error: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResumableQuery {
+    user_id: i64,
+    kind: String,
+    #[serde(default)]
+    site: Site,
+}
+
+pub async fn start_sync(
+    State(AppState { database, .. }): State<AppState>,
+    Json(StartSync {
+        kind,
+        user_id,
+        goal,
+        site,
+    }): Json<StartSync>,
+) -> AppResult<Json<Value>> {
+    if !matches!(kind.as_str(), "sync" | "full") {
+        return Err(
+            BadRequest(format!("unknown sync kind '{kind}', expected sync or full")).into(),
+        );
+    }
+    let id = database
+        .start_sync_session(site, &kind, user_id, goal)
+        .await?;
+    json_ok!({ "id": id })
+}
+
+pub async fn checkpoint_sync(
+    State(AppState {
+        database, events, ..
+    }): State<AppState>,
+    Path(session_id): Path<i64>,
+    Json(Checkpoint { pid, downloaded }): Json<Checkpoint>,
+) -> AppResult<Json<Value>> {
+    if !database
+        .checkpoint_sync_session(session_id, pid, downloaded)
+        .await?
+    {
+        return Err(NotFound(format!("sync session {session_id} not found")).into());
+    }
+    events.publish(ProgressEvent::SyncCheckpoint {
+        session_id,
+        pid,
+        downloaded,
+    });
+    json_ok!({ "ok": true })
+}
+
+pub async fn report_sync_error(
+    State(AppState {
+        database, events, ..
+    }): State<AppState>,
+    Path(session_id): Path<i64>,
+    Json(ReportError { post_id, error }): Json<ReportError>,
+) -> AppResult<Json<Value>> {
+    if database.get_sync_session(session_id).await?.is_none() {
+        return Err(NotFound(format!("sync session {session_id} not found")).into());
+    }
+    database.add_sync_error(session_id, post_id, &error).await?;
+    events.publish(ProgressEvent::SyncError {
+        session_id,
+        post_id,
+        error,
+    });
+    json_ok!({ "ok": true })
+}
+
+pub async fn finish_sync(
+    State(AppState {
+        database, events, ..
+    }): State<AppState>,
+    Path(session_id): Path<i64>,
+) -> AppResult<Json<Value>> {
+    if !database.finish_sync_session(session_id).await? {
+        return Err(NotFound(format!(
+            "sync session {session_id} not found or already finished"
+        ))
+        .into());
+    }
+    events.publish(ProgressEvent::SyncFinished { session_id });
+    json_ok!({ "ok": true })
+}
+
+pub async fn get_sync_history(
+    State(AppState { database, .. }): State<AppState>,
+) -> AppResult<Json<Value>> {
+    json_ok!({ "sessions": database.get_sync_sessions().await? })
+}
+
+pub async fn get_sync_session(
+    State(AppState { database, .. }): State<AppState>,
+    Path(session_id): Path<i64>,
+) -> AppResult<Json<Value>> {
+    let Some(session) = database.get_sync_session(session_id).await? else {
+        return Err(NotFound(format!("sync session {session_id} not found")).into());
+    };
+    let errors = database.get_sync_errors(session_id).await?;
+    json_ok!({ "session": session, "errors": errors })
+}
+
+/// Returns the latest unfinished session of the user so an interrupted sync can continue from its
+/// last checkpoint, `null` if there is none.
+pub async fn get_resumable_sync(
+    State(AppState { database, .. }): State<AppState>,
+    Query(ResumableQuery {
+        user_id,
+        kind,
+        site,
+    }): Query<ResumableQuery>,
+) -> AppResult<Json<Value>> {
+    json_ok!({ "session": database.get_resumable_sync_session(site, user_id, &kind).await? })
+}
+
+impl Database {
+    async fn start_sync_session(
+        &self,
+        site: Site,
+        kind: &str,
+        user_id: i64,
+        goal: i64,
+    ) -> Result<i64> {
+        let site = site.as_str();
+        Ok(sqlx::query_scalar!(
+            r#"INSERT INTO sync_sessions (site, kind, user_id, goal)
+            VALUES (?, ?, ?, ?)
+            RETURNING id"#,
+            site,
+            kind,
+            user_id,
+            goal
+        )
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn checkpoint_sync_session(&self, id: i64, pid: i64, downloaded: i64) -> Result<bool> {
+        Ok(sqlx::query!(
+            r#"UPDATE sync_sessions
+            SET pid = ?, downloaded = ?, updated_at = CURRENT_TIMESTAMP
+            WHERE id = ?"#,
+            pid,
+            downloaded,
+            id
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0)
+    }
+
+    async fn add_sync_error(&self, id: i64, post_id: Option<i64>, error: &str) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO sync_errors (session_id, post_id, error) VALUES (?, ?, ?)",
+            id,
+            post_id,
+            error
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn finish_sync_session(&self, id: i64) -> Result<bool> {
+        Ok(sqlx::query!(
+            r#"UPDATE sync_sessions
+            SET finished_at = CURRENT_TIMESTAMP, updated_at = CURRENT_TIMESTAMP
+            WHERE id = ? AND finished_at IS NULL"#,
+            id
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0)
+    }
+
+    async fn get_sync_sessions(&self) -> Result<Vec<SyncSession>> {
+        Ok(sqlx::query_as!(
+            SyncSession,
+            r#"SELECT s.id, s.site, s.kind, s.user_id, s.goal, s.pid, s.downloaded,
+                (SELECT COUNT(1) FROM sync_errors e WHERE e.session_id = s.id) AS "errors!: i64",
+                s.started_at, s.updated_at, s.finished_at AS "finished_at: NaiveDateTime",
+                CAST(unixepoch(COALESCE(s.finished_at, s.updated_at)) - unixepoch(s.started_at)
+                    AS INTEGER) AS "duration!: i64"
+            FROM sync_sessions s
+            ORDER BY s.id DESC
+            LIMIT ?"#,
+            HISTORY_LIMIT
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn get_sync_session(&self, id: i64) -> Result<Option<SyncSession>> {
+        Ok(sqlx::query_as!(
+            SyncSession,
+            r#"SELECT s.id, s.site, s.kind, s.user_id, s.goal, s.pid, s.downloaded,
+                (SELECT COUNT(1) FROM sync_errors e WHERE e.session_id = s.id) AS "errors!: i64",
+                s.started_at, s.updated_at, s.finished_at AS "finished_at: NaiveDateTime",
+                CAST(unixepoch(COALESCE(s.finished_at, s.updated_at)) - unixepoch(s.started_at)
+                    AS INTEGER) AS "duration!: i64"
+            FROM sync_sessions s
+            WHERE s.id = ?"#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn get_resumable_sync_session(
+        &self,
+        site: Site,
+        user_id: i64,
+        kind: &str,
+    ) -> Result<Option<SyncSession>> {
+        let site = site.as_str();
+        Ok(sqlx::query_as!(
+            SyncSession,
+            r#"SELECT s.id, s.site, s.kind, s.user_id, s.goal, s.pid, s.downloaded,
+                (SELECT COUNT(1) FROM sync_errors e WHERE e.session_id = s.id) AS "errors!: i64",
+                s.started_at, s.updated_at, s.finished_at AS "finished_at: NaiveDateTime",
+                CAST(unixepoch(COALESCE(s.finished_at, s.updated_at)) - unixepoch(s.started_at)
+                    AS INTEGER) AS "duration!: i64"
+            FROM sync_sessions s
+            WHERE s.site = ? AND s.user_id = ? AND s.kind = ? AND s.finished_at IS NULL
+            ORDER BY s.id DESC
+            LIMIT 1"#,
+            site,
+            user_id,
+            kind
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn get_sync_errors(&self, id: i64) -> Result<Vec<SyncError>> {
+        Ok(sqlx::query_as!(
+            SyncError,
+            r#"SELECT post_id, error, created_at
+            FROM sync_errors
+            WHERE session_id = ?
+            ORDER BY id"#,
+            id
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+}
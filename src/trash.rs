@@ -0,0 +1,99 @@
+use std::{
+    io::ErrorKind,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::media_processor::file_name;
+
+const TRASH_DIR: &str = ".trash";
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Moves the files of a deleted post to `.trash/<unix timestamp>/`, keeping their layout relative
+/// to the library so they can be restored by moving them back.
+pub async fn trash_post_files(
+    base_path: &Utf8Path,
+    id: i64,
+    external_id: i64,
+    extension: &str,
+) -> Result<Utf8PathBuf> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let trash = base_path.join(TRASH_DIR).join(timestamp.to_string());
+
+    let name = file_name(id, external_id, extension);
+    for path in [
+        Utf8PathBuf::from(&name),
+        Utf8Path::new(".thumbs").join(format!("{name}.jpeg")),
+        Utf8Path::new(".minis").join(format!("mini_{name}.jpeg")),
+    ] {
+        let source = base_path.join(&path);
+        if !source.is_file() {
+            continue;
+        }
+        let target = trash.join(&path);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(&source, &target)
+            .await
+            .with_context(|| format!("failed to move {path} to {target}"))?;
+    }
+    Ok(trash)
+}
+
+/// Periodically removes trash directories older than `days`.
+pub fn spawn_purge(
+    base_path: &Utf8Path,
+    days: u64,
+    shutdown_token: &CancellationToken,
+) -> JoinHandle<()> {
+    let trash = base_path.join(TRASH_DIR);
+    let shutdown_token = shutdown_token.clone();
+    let max_age = Duration::from_secs(days.saturating_mul(24 * 60 * 60));
+
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = purge(&trash, max_age).await {
+                warn!("Failed to purge trash: {err:#}");
+            }
+            tokio::select! {
+                _ = shutdown_token.cancelled() => break,
+                _ = tokio::time::sleep(PURGE_INTERVAL) => {},
+            }
+        }
+    })
+}
+
+async fn purge(trash: &Utf8Path, max_age: Duration) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let mut entries = match tokio::fs::read_dir(trash).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context(format!("failed to read {trash}")),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let Some(trashed_at) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        if now.saturating_sub(Duration::from_secs(trashed_at)) < max_age {
+            continue;
+        }
+
+        let path = entry.path();
+        tokio::fs::remove_dir_all(&path)
+            .await
+            .with_context(|| format!("failed to remove {path:?}"))?;
+        info!("Purged {path:?} from trash");
+    }
+    Ok(())
+}
@@ -1,54 +1,188 @@
-use std::io::Write;
+use std::{collections::HashSet, io::Write};
 
 use anyhow::{Context, Result};
 use axum::{
     Json,
-    extract::{Multipart, State},
+    extract::{Multipart, Query, State},
 };
+use camino::Utf8Path;
+use md5::Md5;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use sqlx::SqliteConnection;
 use tempfile::NamedTempFile;
+use tokio::{fs::File, io::AsyncReadExt};
 use tracing::info;
 
 use crate::{
     database::Database,
+    events::ProgressEvent,
     json_ok,
-    media_processor::MediaProcessor,
-    server::{AppResult, AppState},
+    media_processor::{
+        MediaMetadata, MediaProcessor, file_name, link_post_files, remove_post_files,
+    },
+    server::{AppResult, AppState, BadRequest, ServerOptions},
+    site::{Site, SiteQuery},
 };
 
+/// Queues the upload to be processed in the background, see [`crate::jobs`].
 pub async fn upload(
     State(AppState {
         database,
         base_path,
+        jobs,
+        events,
         ..
     }): State<AppState>,
     multipart: Multipart,
 ) -> AppResult<Json<Value>> {
     let data = PostData::from_multipart(multipart).await?;
+    data.verify_md5()?;
+    let post_id = data.id;
+    let job_id = jobs.enqueue(&database, &base_path, data).await?;
+    events.publish(ProgressEvent::UploadReceived { job_id, post_id });
+    json_ok!({"ok": true, "jobId": job_id, "state": "queued"})
+}
+
+/// Stores an uploaded post, returning a summary of what was done.
+pub async fn store_post(
+    database: &Database,
+    base_path: &Utf8Path,
+    options: &ServerOptions,
+    data: PostData,
+) -> Result<Value> {
+    if let Some(existing) = database.get_stored_post(data.site, data.id).await? {
+        return update(database, base_path, existing, data).await;
+    }
+
+    let shared = if options.dedupe {
+        database.get_post_by_sha256(&data.hashes.sha256).await?
+    } else {
+        None
+    };
+    if let Some(shared) = shared {
+        let shared_name = file_name(shared.id, shared.external_id, &shared.metadata.extension);
+        if base_path.join(&shared_name).is_file() {
+            return insert_shared(database, base_path, shared, &shared_name, data).await;
+        }
+    }
+
     let processor = MediaProcessor::process(data.image).await?;
     let post_id = database
         .insert_post(
+            data.site,
+            data.id,
+            &processor.metadata,
+            &data.hashes,
+            &data.metadata,
+            data.tags.as_deref().unwrap_or_default(),
+        )
+        .await?;
+    info!("Saved {}", data.site.post_url(data.id));
+    processor.commit(base_path, post_id, data.id).await?;
+    Ok(json!({"ok": true, "created": true}))
+}
+
+/// Stores a post whose content is already in the library by hard linking the existing files
+/// instead of processing and storing the upload again.
+async fn insert_shared(
+    database: &Database,
+    base_path: &Utf8Path,
+    shared: SharedPost,
+    shared_name: &str,
+    data: PostData,
+) -> Result<Value> {
+    let post_id = database
+        .insert_post(
+            data.site,
             data.id,
-            processor.extension,
-            processor.mime,
-            processor.original,
-            &data.tags,
+            &shared.metadata,
+            &data.hashes,
+            &data.metadata,
+            data.tags.as_deref().unwrap_or_default(),
         )
         .await?;
+    link_post_files(
+        base_path,
+        shared_name,
+        &file_name(post_id, data.id, &shared.metadata.extension),
+    )
+    .await?;
+    info!(
+        "Saved {} (same content as {} post {})",
+        data.site.post_url(data.id),
+        shared.site,
+        shared.external_id
+    );
+    Ok(json!({"ok": true, "created": true, "sharedWith": shared.external_id}))
+}
+
+/// Uploads of already downloaded posts refresh the stored tags and only replace the file if asked
+/// to.
+async fn update(
+    database: &Database,
+    base_path: &Utf8Path,
+    existing: StoredPost,
+    data: PostData,
+) -> Result<Value> {
+    // Posts whose file is gone or broken are repaired by uploading them again.
+    let replace = data.replace
+        || database.is_flagged_for_redownload(existing.id).await?
+        || !base_path
+            .join(file_name(existing.id, data.id, &existing.extension))
+            .is_file();
+    // Processed before anything is changed, so a file that can't be processed leaves the post as
+    // it was.
+    let processor = if replace {
+        Some(MediaProcessor::process(data.image).await?)
+    } else {
+        None
+    };
+
+    let changes = match &data.tags {
+        Some(tags) => database.update_post_tags(existing.id, tags).await?,
+        None => TagChanges::default(),
+    };
+    database
+        .update_post_metadata(existing.id, &data.metadata)
+        .await?;
+
+    if let Some(processor) = processor {
+        remove_post_files(base_path, existing.id, data.id, &existing.extension).await?;
+        database
+            .update_post_media(existing.id, &processor.metadata, &data.hashes)
+            .await?;
+        processor.commit(base_path, existing.id, data.id).await?;
+        database.clear_redownload(existing.id).await?;
+    } else {
+        database.fill_post_hashes(existing.id, &data.hashes).await?;
+    }
+
     info!(
-        "Saved https://rule34.xxx/index.php?page=post&s=view&id={}",
-        data.id
+        "Updated {} (+{} -{} tags{})",
+        data.site.post_url(data.id),
+        changes.added.len(),
+        changes.removed.len(),
+        if replace { ", replaced file" } else { "" }
     );
-    processor.commit(&base_path, post_id, data.id).await?;
-    json_ok!({"ok": true})
+    Ok(json!({
+        "ok": true,
+        "created": false,
+        "addedTags": changes.added,
+        "removedTags": changes.removed,
+        "replacedFile": replace,
+    }))
 }
 
 pub async fn check_download_status(
     State(AppState { database, .. }): State<AppState>,
+    Query(SiteQuery { site }): Query<SiteQuery>,
     Json(PostIdsResponse { post_ids }): Json<PostIdsResponse>,
 ) -> AppResult<Json<PostIdsResponse>> {
-    let existing_ids = database.filter_already_downloaded_posts(&post_ids).await?;
+    let existing_ids = database
+        .filter_already_downloaded_posts(site, &post_ids)
+        .await?;
     Ok(Json(PostIdsResponse {
         post_ids: existing_ids,
     }))
@@ -56,18 +190,25 @@ pub async fn check_download_status(
 
 pub async fn get_download_count(
     State(AppState { database, .. }): State<AppState>,
+    Query(SiteQuery { site }): Query<SiteQuery>,
 ) -> AppResult<Json<Value>> {
-    json_ok!({"count": database.get_download_count().await?})
+    json_ok!({"count": database.get_download_count(site).await?})
 }
 
 impl Database {
-    pub async fn filter_already_downloaded_posts(&self, post_ids: &[i64]) -> Result<Vec<i64>> {
+    pub async fn filter_already_downloaded_posts(
+        &self,
+        site: Site,
+        post_ids: &[i64],
+    ) -> Result<Vec<i64>> {
         if post_ids.is_empty() {
             return Ok(Vec::new());
         }
 
         let mut query_builder =
-            sqlx::QueryBuilder::new("SELECT external_id FROM posts WHERE external_id IN (");
+            sqlx::QueryBuilder::new("SELECT external_id FROM posts WHERE site = ");
+        query_builder.push_bind(site.as_str());
+        query_builder.push(" AND external_id IN (");
         query_builder.push_values(post_ids, |mut builder, post_id| {
             builder.push_bind(post_id);
         });
@@ -79,51 +220,162 @@ impl Database {
             .await?)
     }
 
+    pub async fn get_stored_post(
+        &self,
+        site: Site,
+        external_id: i64,
+    ) -> Result<Option<StoredPost>> {
+        let site = site.as_str();
+        Ok(sqlx::query_as!(
+            StoredPost,
+            "SELECT id, extension FROM posts WHERE site = ? AND external_id = ?",
+            site,
+            external_id
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    pub async fn get_post_by_sha256(&self, sha256: &str) -> Result<Option<SharedPost>> {
+        let post = sqlx::query!(
+            r#"SELECT id, site, external_id, extension, mime, original, phash, width, height,
+                file_size, duration, frame_rate, codec, has_audio
+            FROM posts
+            WHERE sha256 = ?
+            ORDER BY id
+            LIMIT 1"#,
+            sha256
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(post.map(|post| SharedPost {
+            id: post.id,
+            site: post.site,
+            external_id: post.external_id,
+            metadata: MediaMetadata {
+                mime: post.mime,
+                extension: post.extension,
+                original: post.original,
+                phash: post.phash,
+                width: post.width,
+                height: post.height,
+                file_size: post.file_size,
+                duration: post.duration,
+                frame_rate: post.frame_rate,
+                codec: post.codec,
+                has_audio: post.has_audio,
+            },
+        }))
+    }
+
     pub async fn insert_post(
         &self,
+        site: Site,
         external_id: i64,
-        extension: &str,
-        mime: &str,
-        original: bool,
+        metadata: &MediaMetadata,
+        hashes: &ContentHashes,
+        site_metadata: &SiteMetadata,
         tags: &[Tag],
     ) -> Result<i64> {
         let mut trx = self.pool.begin().await?;
 
-        let id = sqlx::query_scalar!("SELECT id FROM posts WHERE external_id = ?", external_id)
-            .fetch_optional(&mut *trx)
-            .await?;
+        let rating = site_metadata.rating.map(|rating| rating.as_str());
+        let site = site.as_str();
+        let id = sqlx::query_scalar!(
+            r#"INSERT INTO posts (
+                site, external_id, extension, mime, original, sha256, md5, phash,
+                width, height, file_size, duration, frame_rate, codec, has_audio,
+                rating, score, source, parent_id
+            ) 
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) 
+            RETURNING id"#,
+            site,
+            external_id,
+            metadata.extension,
+            metadata.mime,
+            metadata.original,
+            hashes.sha256,
+            hashes.md5,
+            metadata.phash,
+            metadata.width,
+            metadata.height,
+            metadata.file_size,
+            metadata.duration,
+            metadata.frame_rate,
+            metadata.codec,
+            metadata.has_audio,
+            rating,
+            site_metadata.score,
+            site_metadata.source,
+            site_metadata.parent_id
+        )
+        .fetch_one(&mut *trx)
+        .await?;
+
+        add_post_tags(&mut trx, id, tags.iter()).await?;
+
+        trx.commit().await?;
+
+        Ok(id)
+    }
 
-        let id = if let Some(id) = id {
+    /// Makes the tags of a post match `tags`, returning the names of the added and removed tags.
+    /// Every change is recorded in the tag history of the post.
+    pub async fn update_post_tags(&self, id: i64, tags: &[Tag]) -> Result<TagChanges> {
+        let mut trx = self.pool.begin().await?;
+
+        let current: HashSet<String> = sqlx::query_scalar!(
+            r#"SELECT t.name
+            FROM post_tags pt
+            JOIN tags t ON t.id = pt.tag_id
+            WHERE pt.post_id = ?"#,
             id
-        } else {
-            sqlx::query_scalar!(
-                r#"INSERT INTO posts (external_id, extension, mime, original) 
-                VALUES (?, ?, ?, ?) 
-                RETURNING id"#,
-                external_id,
-                extension,
-                mime,
-                original
+        )
+        .fetch_all(&mut *trx)
+        .await?
+        .into_iter()
+        .collect();
+        let incoming: HashSet<&str> = tags.iter().map(|tag| tag.name.as_str()).collect();
+
+        let added: Vec<&Tag> = tags
+            .iter()
+            .filter(|tag| !current.contains(&tag.name))
+            .collect();
+        let mut removed: Vec<String> = current
+            .into_iter()
+            .filter(|name| !incoming.contains(name.as_str()))
+            .collect();
+        removed.sort();
+
+        add_post_tags(&mut trx, id, added.iter().copied()).await?;
+
+        for tag in &added {
+            sqlx::query!(
+                r#"INSERT INTO post_tag_history (post_id, tag_id, action)
+                VALUES (?, (SELECT id FROM tags WHERE name = ?), 'added')"#,
+                id,
+                tag.name
             )
-            .fetch_one(&mut *trx)
-            .await?
-        };
+            .execute(&mut *trx)
+            .await?;
+        }
 
-        for tag in tags {
-            let kind = tag.kind.as_str();
+        for name in &removed {
             sqlx::query!(
-                "INSERT INTO tags (name, kind) VALUES (?, ?) ON CONFLICT DO NOTHING",
-                tag.name,
-                kind,
+                r#"DELETE FROM post_tags
+                WHERE post_id = ? AND tag_id = (SELECT id FROM tags WHERE name = ?)"#,
+                id,
+                name
             )
             .execute(&mut *trx)
             .await?;
 
             sqlx::query!(
-                r#"INSERT INTO post_tags (post_id, tag_id) 
-                VALUES (?, (SELECT id FROM tags WHERE name = ?))"#,
+                r#"INSERT INTO post_tag_history (post_id, tag_id, action)
+                VALUES (?, (SELECT id FROM tags WHERE name = ?), 'removed')"#,
                 id,
-                tag.name
+                name
             )
             .execute(&mut *trx)
             .await?;
@@ -131,14 +383,132 @@ impl Database {
 
         trx.commit().await?;
 
-        Ok(id)
+        Ok(TagChanges {
+            added: added.into_iter().map(|tag| tag.name.clone()).collect(),
+            removed,
+        })
     }
 
-    async fn get_download_count(&self) -> Result<i64> {
-        Ok(sqlx::query_scalar!("SELECT COUNT(1) FROM posts")
-            .fetch_one(&self.pool)
-            .await?)
+    pub async fn update_post_media(
+        &self,
+        id: i64,
+        metadata: &MediaMetadata,
+        hashes: &ContentHashes,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"UPDATE posts
+            SET extension = ?, mime = ?, original = ?, sha256 = ?, md5 = ?, phash = ?,
+                width = ?, height = ?, file_size = ?, duration = ?, frame_rate = ?, codec = ?,
+                has_audio = ?
+            WHERE id = ?"#,
+            metadata.extension,
+            metadata.mime,
+            metadata.original,
+            hashes.sha256,
+            hashes.md5,
+            metadata.phash,
+            metadata.width,
+            metadata.height,
+            metadata.file_size,
+            metadata.duration,
+            metadata.frame_rate,
+            metadata.codec,
+            metadata.has_audio,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Refreshes what the site says about a post, keeping the stored values the upload didn't
+    /// include.
+    pub async fn update_post_metadata(&self, id: i64, metadata: &SiteMetadata) -> Result<()> {
+        let rating = metadata.rating.map(|rating| rating.as_str());
+        sqlx::query!(
+            r#"UPDATE posts
+            SET rating = COALESCE(?, rating), score = COALESCE(?, score),
+                source = COALESCE(?, source), parent_id = COALESCE(?, parent_id)
+            WHERE id = ?"#,
+            rating,
+            metadata.score,
+            metadata.source,
+            metadata.parent_id,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
     }
+
+    /// Stores the hashes of posts that were downloaded before hashes were recorded.
+    pub async fn fill_post_hashes(&self, id: i64, hashes: &ContentHashes) -> Result<()> {
+        sqlx::query!(
+            r#"UPDATE posts
+            SET sha256 = COALESCE(sha256, ?), md5 = COALESCE(md5, ?)
+            WHERE id = ?"#,
+            hashes.sha256,
+            hashes.md5,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_download_count(&self, site: Site) -> Result<i64> {
+        let site = site.as_str();
+        Ok(
+            sqlx::query_scalar!("SELECT COUNT(1) FROM posts WHERE site = ?", site)
+                .fetch_one(&self.pool)
+                .await?,
+        )
+    }
+}
+
+async fn add_post_tags(
+    connection: &mut SqliteConnection,
+    id: i64,
+    tags: impl Iterator<Item = &Tag>,
+) -> Result<()> {
+    for tag in tags {
+        let kind = tag.kind.as_str();
+        sqlx::query!(
+            "INSERT INTO tags (name, kind) VALUES (?, ?) ON CONFLICT DO NOTHING",
+            tag.name,
+            kind,
+        )
+        .execute(&mut *connection)
+        .await?;
+
+        sqlx::query!(
+            r#"INSERT INTO post_tags (post_id, tag_id) 
+            VALUES (?, (SELECT id FROM tags WHERE name = ?))"#,
+            id,
+            tag.name
+        )
+        .execute(&mut *connection)
+        .await?;
+    }
+    Ok(())
+}
+
+pub struct StoredPost {
+    pub id: i64,
+    pub extension: String,
+}
+
+pub struct SharedPost {
+    pub id: i64,
+    pub site: String,
+    pub external_id: i64,
+    pub metadata: MediaMetadata,
+}
+
+#[derive(Default)]
+pub struct TagChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -148,9 +518,57 @@ pub struct PostIdsResponse {
 }
 
 pub struct PostData {
+    pub site: Site,
     pub id: i64,
     pub image: NamedTempFile,
-    pub tags: Vec<Tag>,
+    pub hashes: ContentHashes,
+    /// `None` if the upload did not include tags, which leaves the tags of existing posts alone.
+    pub tags: Option<Vec<Tag>>,
+    /// Replace the file of an already downloaded post.
+    pub replace: bool,
+    pub metadata: SiteMetadata,
+}
+
+/// What the site says about a post. Everything is optional, older userscripts don't send it.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SiteMetadata {
+    pub rating: Option<Rating>,
+    pub score: Option<i64>,
+    pub source: Option<String>,
+    pub parent_id: Option<i64>,
+    /// md5 of the original file, which tells if the download was truncated.
+    pub md5: Option<String>,
+}
+
+/// Hashes of the uploaded file, before any recompression.
+pub struct ContentHashes {
+    pub sha256: String,
+    /// Same hash the booru sites use to identify files.
+    pub md5: String,
+}
+
+impl ContentHashes {
+    pub async fn of_file(path: &Utf8Path) -> Result<Self> {
+        let mut file = File::open(path)
+            .await
+            .with_context(|| format!("failed to open {path}"))?;
+        let mut sha256 = Sha256::new();
+        let mut md5 = Md5::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            sha256.update(&buffer[..read]);
+            md5.update(&buffer[..read]);
+        }
+        Ok(Self {
+            sha256: format!("{:x}", sha256.finalize()),
+            md5: format!("{:x}", md5.finalize()),
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -159,7 +577,7 @@ pub struct Tag {
     pub kind: TagKind,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TagKind {
     Copyright,
@@ -170,7 +588,18 @@ pub enum TagKind {
 }
 
 impl TagKind {
-    fn as_str(&self) -> &'static str {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "copyright" => Some(Self::Copyright),
+            "character" => Some(Self::Character),
+            "artist" => Some(Self::Artist),
+            "general" => Some(Self::General),
+            "metadata" => Some(Self::Metadata),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
         match self {
             Self::Copyright => "copyright",
             Self::Character => "character",
@@ -181,11 +610,56 @@ impl TagKind {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    Safe,
+    Questionable,
+    Explicit,
+}
+
+impl Rating {
+    /// Accepts the full names, their first letter and the `general` and `sensitive` ratings of
+    /// newer Gelbooru versions.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "s" | "safe" | "g" | "general" => Some(Self::Safe),
+            "q" | "questionable" | "sensitive" => Some(Self::Questionable),
+            "e" | "explicit" => Some(Self::Explicit),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Safe => "safe",
+            Self::Questionable => "questionable",
+            Self::Explicit => "explicit",
+        }
+    }
+}
+
 impl PostData {
+    /// Rejects files whose md5 differs from the one the site reported, which usually means the
+    /// download was cut off.
+    pub fn verify_md5(&self) -> Result<(), BadRequest> {
+        match &self.metadata.md5 {
+            Some(md5) if !md5.eq_ignore_ascii_case(&self.hashes.md5) => Err(BadRequest(format!(
+                "md5 of post {} is {} but the site reported {md5}, the download is probably \
+                incomplete",
+                self.id, self.hashes.md5
+            ))),
+            _ => Ok(()),
+        }
+    }
+
     pub async fn from_multipart(mut value: Multipart) -> Result<Self> {
+        let mut site = Site::default();
         let mut id: Option<i64> = None;
-        let mut image: Option<NamedTempFile> = None;
+        let mut image: Option<(NamedTempFile, ContentHashes)> = None;
         let mut tags: Option<Vec<Tag>> = None;
+        let mut replace = false;
+        let mut metadata = SiteMetadata::default();
 
         while let Some(mut field) = value
             .next_field()
@@ -195,6 +669,11 @@ impl PostData {
             let name = field.name().unwrap_or("").to_string();
 
             match name.as_str() {
+                "site" => {
+                    let data = field.text().await?;
+                    site = Site::parse(data.trim())
+                        .ok_or_else(|| BadRequest(format!("unknown site '{data}'")))?;
+                }
                 "id" => {
                     let data = field.text().await?;
                     id = Some(
@@ -206,17 +685,62 @@ impl PostData {
                 "image" => {
                     let mut tmp =
                         NamedTempFile::new().context("Failed to create temp file for image")?;
+                    let mut sha256 = Sha256::new();
+                    let mut md5 = Md5::new();
                     while let Some(chunk) = field.chunk().await? {
+                        sha256.update(&chunk);
+                        md5.update(&chunk);
                         tmp.write_all(&chunk).context("Failed writing image data")?;
                     }
-                    image = Some(tmp);
+                    let hashes = ContentHashes {
+                        sha256: format!("{:x}", sha256.finalize()),
+                        md5: format!("{:x}", md5.finalize()),
+                    };
+                    image = Some((tmp, hashes));
                 }
                 "tags" => {
                     let data = field.text().await?;
-                    let t: Vec<Tag> =
+                    let mut t: Vec<Tag> =
                         serde_json::from_str(&data).context("Invalid JSON for tags")?;
+                    // A tag listed twice would be added to the post twice.
+                    let mut seen = HashSet::new();
+                    t.retain(|tag| seen.insert(tag.name.clone()));
                     tags = Some(t);
                 }
+                "replace" => {
+                    replace = matches!(field.text().await?.trim(), "true" | "1");
+                }
+                "rating" => {
+                    let data = field.text().await?;
+                    metadata.rating = Some(
+                        Rating::parse(data.trim())
+                            .ok_or_else(|| BadRequest(format!("unknown rating '{data}'")))?,
+                    );
+                }
+                "score" => {
+                    let data = field.text().await?;
+                    metadata.score = Some(
+                        data.trim()
+                            .parse::<i64>()
+                            .map_err(|_| BadRequest(format!("invalid score '{data}'")))?,
+                    );
+                }
+                "source" => {
+                    let data = field.text().await?;
+                    metadata.source =
+                        Some(data.trim().to_string()).filter(|source| !source.is_empty());
+                }
+                "parentId" => {
+                    let data = field.text().await?;
+                    metadata.parent_id = Some(
+                        data.trim()
+                            .parse::<i64>()
+                            .map_err(|_| BadRequest(format!("invalid parentId '{data}'")))?,
+                    );
+                }
+                "md5" => {
+                    metadata.md5 = Some(field.text().await?.trim().to_ascii_lowercase());
+                }
                 _ => {
                     // Ignore unknown fields
                 }
@@ -225,9 +749,16 @@ impl PostData {
 
         // Validate required fields
         let id = id.ok_or_else(|| anyhow::anyhow!("missing field: id"))?;
-        let image = image.ok_or_else(|| anyhow::anyhow!("missing field: image"))?;
-        let tags = tags.unwrap_or_default();
+        let (image, hashes) = image.ok_or_else(|| anyhow::anyhow!("missing field: image"))?;
 
-        Ok(PostData { id, image, tags })
+        Ok(PostData {
+            site,
+            id,
+            image,
+            hashes,
+            tags,
+            replace,
+            metadata,
+        })
     }
 }
@@ -1,5 +1,5 @@
 import van from "vanjs-core"
-import { AutoCompleteSuggestion, getAutocompleteSuggestions, searchFavorites } from "../network"
+import { AutoCompleteSuggestion, SearchError, getAutocompleteSuggestions, searchFavorites } from "../network"
 import { FavoritesSearchResult } from "./FavoritesSearchResult"
 import { addToHistory, getHistory } from "./FavoritesSearchHistory"
 
@@ -54,8 +54,19 @@ export function FavoritesSearch() {
 				e.preventDefault()
 				e.stopPropagation()
 
-				const term = (e.target as HTMLFormElement & { search: HTMLInputElement }).search.value.trim()
-				const data = await searchFavorites(term)
+				const searchInput = (e.target as HTMLFormElement & { search: HTMLInputElement }).search
+				const term = searchInput.value.trim()
+				let data: number[]
+				try {
+					data = await searchFavorites(term)
+				} catch (err) {
+					if (!(err instanceof SearchError)) throw err
+					searchInput.setCustomValidity(`${err.message} (at position ${err.position})`)
+					searchInput.reportValidity()
+					const start = searchInput.value.indexOf(term) + err.position
+					searchInput.setSelectionRange(start, start + term.length - err.position)
+					return
+				}
 				van.add(document.body, FavoritesSearchResult(term, data))
 				addToHistory({ term, results: data.length })
 				inputActive.val = false
@@ -77,6 +88,7 @@ export function FavoritesSearch() {
 			oninput: (e: Event) => {
 				inputActive.val = true
 				const el = e.target as HTMLInputElement
+				el.setCustomValidity("")
 				search.val = el.value
 
 				fetchSuggestions(getLastWord(search.val))
@@ -1,4 +1,5 @@
 import van from "vanjs-core"
+import { getMiniImageUrl } from "../network"
 
 const { div, h1, span, a, button, img } = van.tags
 
@@ -40,7 +41,7 @@ export function FavoritesSearchResult(term: string, postIds: number[]) {
 							span(`${postIds.length} result${postIds.length > 1 ? "s" : ""} for `),
 							a(
 								{
-									href: `https://rule34.xxx/index.php?page=post&s=list&tags=${term}`,
+									href: `/index.php?page=post&s=list&tags=${term}`,
 									target: "_blank",
 								},
 								term,
@@ -53,11 +54,11 @@ export function FavoritesSearchResult(term: string, postIds: number[]) {
 						ids.val.map((id) => {
 							return a(
 								{
-									href: `https://rule34.xxx/index.php?page=post&s=view&id=${id}&tags=${term}`,
+									href: `/index.php?page=post&s=view&id=${id}&tags=${term}`,
 									target: "_blank",
 								},
 								img({
-									src: `http://localhost:34343/image/mini/${id}`,
+									src: getMiniImageUrl(id),
 									width: "300",
 									loading: "lazy",
 								}),
@@ -2,12 +2,21 @@ import { PostData, TagKind } from "./sync"
 
 const ARUESHALAE_API_URL = "http://localhost:34343"
 
+// Post ids are only unique per site, so the server is told which one the script runs on
+const SITES: Record<string, string> = {
+	"rule34.xxx": "rule34",
+	"gelbooru.com": "gelbooru",
+	"safebooru.org": "safebooru",
+}
+const SITE = SITES[location.hostname] ?? "rule34"
+
 const MAX_RETRIES = 15
 const JITTER_MS = 30
 const BASE_DELAY = 100
 const FAIL_INCREASE = 2
 const SUCCESS_DECREASE = 0.9
 const SUCCESS_STREAK = 5
+const JOB_POLL_INTERVAL = 500
 
 let currentDelay = BASE_DELAY
 let currentStreak = 0
@@ -22,9 +31,13 @@ export function fetchImage(url: string): Promise<Blob> {
 
 export async function upload(post: PostData) {
 	const formData = new FormData()
+	formData.append("site", SITE)
 	formData.append("id", post.id.toString())
 	formData.append("image", post.image)
 	formData.append("tags", JSON.stringify(post.tags))
+	for (const [name, value] of Object.entries(post.metadata)) {
+		if (value !== undefined) formData.append(name, value.toString())
+	}
 
 	const response = await fetch(`${ARUESHALAE_API_URL}/upload`, {
 		method: "POST",
@@ -35,11 +48,18 @@ export async function upload(post: PostData) {
 		throw new Error(`Upload for post #${post.id} did not succeed. Expected status 200, got ${response.status}`)
 	}
 
-	return
+	// The server processes uploads in the background, wait for it so the post is stored afterwards
+	const { jobId } = await response.json()
+	while (true) {
+		await sleep(JOB_POLL_INTERVAL)
+		const job = await (await fetch(`${ARUESHALAE_API_URL}/jobs/${jobId}`)).json()
+		if (job.state === "done") return
+		if (job.state === "failed") throw new Error(`Processing post #${post.id} failed: ${job.error}`)
+	}
 }
 
 export async function filterForDownloadedIds(ids: number[]): Promise<number[]> {
-	const response = await fetch(`${ARUESHALAE_API_URL}/check`, {
+	const response = await fetch(`${ARUESHALAE_API_URL}/check?site=${SITE}`, {
 		method: "POST",
 		headers: {
 			"Content-Type": "application/json",
@@ -60,15 +80,94 @@ export async function checkIfDownloaded(id: number): Promise<number | null> {
 }
 
 export async function getDownloadedCount(): Promise<number> {
-	const response = await fetch(`${ARUESHALAE_API_URL}/count`)
+	const response = await fetch(`${ARUESHALAE_API_URL}/count?site=${SITE}`)
 	return (await response.json()).count
 }
 
+export interface SyncSession {
+	id: number
+	pid: number | null
+	downloaded: number
+}
+
+export async function startSyncSession(kind: "sync" | "full", userId: number, goal: number): Promise<number> {
+	const response = await postJson("/sync", { kind, userId, goal, site: SITE })
+	return (await response.json()).id
+}
+
+// Latest unfinished session, which a full sync continues from its last checkpoint
+export async function getResumableSyncSession(userId: number, kind: "sync" | "full"): Promise<SyncSession | null> {
+	const response = await fetch(`${ARUESHALAE_API_URL}/sync/resumable?userId=${userId}&kind=${kind}&site=${SITE}`)
+	return (await response.json()).session
+}
+
+export async function checkpointSyncSession(id: number, pid: number, downloaded: number) {
+	await postJson(`/sync/${id}/checkpoint`, { pid, downloaded })
+}
+
+export async function reportSyncError(id: number, postId: number | null, error: string) {
+	await postJson(`/sync/${id}/error`, { postId, error })
+}
+
+export async function finishSyncSession(id: number) {
+	await fetch(`${ARUESHALAE_API_URL}/sync/${id}/finish`, { method: "POST" })
+}
+
+function postJson(path: string, body: unknown): Promise<Response> {
+	return fetch(`${ARUESHALAE_API_URL}${path}`, {
+		method: "POST",
+		headers: {
+			"Content-Type": "application/json",
+		},
+		body: JSON.stringify(body),
+	})
+}
+
+export async function addReconcileChunk(session: string, ids: number[]) {
+	await fetch(`${ARUESHALAE_API_URL}/reconcile/${session}?site=${SITE}`, {
+		method: "POST",
+		headers: {
+			"Content-Type": "application/json",
+		},
+		body: JSON.stringify({ postIds: ids }),
+	})
+}
+
+// Posts that are downloaded but not in the favorites sent for the session
+export async function finishReconcile(session: string): Promise<number[]> {
+	const response = await fetch(`${ARUESHALAE_API_URL}/reconcile/${session}/finish?site=${SITE}`, { method: "POST" })
+	return (await response.json()).unfavorited
+}
+
+export async function getRedownloadIds(): Promise<number[]> {
+	const response = await fetch(`${ARUESHALAE_API_URL}/redownload?site=${SITE}`)
+	return (await response.json()).posts.map((post: { id: number }) => post.id)
+}
+
+export class SearchError extends Error {
+	position: number
+
+	constructor(message: string, position: number) {
+		super(message)
+		this.position = position
+	}
+}
+
 export async function searchFavorites(term: string): Promise<number[]> {
-	const response = await fetch(`${ARUESHALAE_API_URL}/search?term=${term}`)
+	const response = await fetch(`${ARUESHALAE_API_URL}/search?term=${encodeURIComponent(term)}&site=${SITE}`)
+	if (response.status === 400) {
+		const { error, position } = await response.json()
+		// The server counts bytes of the UTF-8 encoded term, inputs count UTF-16 code units
+		const before = new TextEncoder().encode(term).subarray(0, position)
+		throw new SearchError(error, new TextDecoder().decode(before).length)
+	}
 	return (await response.json()).postIds
 }
 
+export function getMiniImageUrl(id: number): string {
+	return `${ARUESHALAE_API_URL}/image/mini/${id}?site=${SITE}`
+}
+
 export interface AutoCompleteSuggestion {
 	name: string
 	kind: TagKind
@@ -1,5 +1,19 @@
 import { State } from "vanjs-core"
-import { fetchImage, fetchDocument, filterForNotDownloaded, upload, filterForDownloadedIds } from "./network"
+import {
+	fetchImage,
+	fetchDocument,
+	filterForNotDownloaded,
+	upload,
+	filterForDownloadedIds,
+	getRedownloadIds,
+	addReconcileChunk,
+	finishReconcile,
+	startSyncSession,
+	getResumableSyncSession,
+	checkpointSyncSession,
+	reportSyncError,
+	finishSyncSession,
+} from "./network"
 
 // Syncs
 export type SyncProgress =
@@ -23,54 +37,105 @@ export async function sync(
 	let downloaded = 0
 	const difference = totalFavorites - serverFavorites
 	progressState.val = { state: "downloading", downloaded, goal: difference }
+	const sessionId = await startSyncSession("sync", userId, difference)
 
 	// FIXME: change approach to go backwards until it found the missing
 	// Current fix is to load at least 10 pages to check if anything has been missed
 	let pid = Math.max(difference, 500)
+	let currentPostId: number | null = null
 
-	do {
-		pid = Math.max(0, pid - 50)
+	try {
+		do {
+			pid = Math.max(0, pid - 50)
 
-		const favoritesPage = await getFavoritesPage(userId, pid)
-		const postIds = await filterForNotDownloaded(getPostIds(favoritesPage))
-		postIds.reverse()
+			const favoritesPage = await getFavoritesPage(userId, pid)
+			const postIds = await filterForNotDownloaded(getPostIds(favoritesPage))
+			postIds.reverse()
 
-		for (let postId of postIds) {
-			const data = await getPostData(postId)
-			await upload(data)
-			progressState.val = { state: "downloading", downloaded: ++downloaded, goal: difference }
-		}
-	} while (pid > 0)
+			for (let postId of postIds) {
+				currentPostId = postId
+				const data = await getPostData(postId)
+				await upload(data)
+				progressState.val = { state: "downloading", downloaded: ++downloaded, goal: difference }
+			}
+			currentPostId = null
+			await checkpointSyncSession(sessionId, pid, downloaded)
+		} while (pid > 0)
+	} catch (error: unknown) {
+		await reportSyncError(sessionId, currentPostId, String(error))
+		throw error
+	}
 
-	progressState.val = { state: "done", message: `Synced ${downloaded} posts.` }
+	const redownloaded = await redownloadBroken()
+	await finishSyncSession(sessionId)
+	progressState.val = { state: "done", message: `Synced ${downloaded} posts.${redownloadMessage(redownloaded)}` }
 }
 
 export async function fullSync(userId: number, totalFavorites: number, progressState: State<SyncProgress>) {
-	let downloaded = 0
+	// Continue an interrupted full sync from the last page it completed
+	const resumable = await getResumableSyncSession(userId, "full")
+	const sessionId = resumable?.id ?? (await startSyncSession("full", userId, totalFavorites))
+	let downloaded = resumable?.downloaded ?? 0
 	progressState.val = { state: "downloading", downloaded, goal: totalFavorites }
 
-	let pid = totalFavorites
-	do {
-		pid = Math.max(0, pid - 50)
+	// Every favorite is seen during a full sync, so the server can tell which posts were unfavorited
+	const reconcileSession = `sync-${sessionId}`
+	let pid = resumable?.pid ?? totalFavorites
+	let currentPostId: number | null = null
+
+	try {
+		do {
+			pid = Math.max(0, pid - 50)
+
+			const favoritesPage = await getFavoritesPage(userId, pid)
+			const postIds = getPostIds(favoritesPage)
+			postIds.reverse()
+			await addReconcileChunk(reconcileSession, postIds)
+			const alreadyDownloaded = await filterForDownloadedIds(postIds)
 
-		const favoritesPage = await getFavoritesPage(userId, pid)
-		const postIds = getPostIds(favoritesPage)
-		postIds.reverse()
-		const alreadyDownloaded = await filterForDownloadedIds(postIds)
+			for (let postId of postIds) {
+				if (alreadyDownloaded.includes(postId)) {
+					progressState.val = { state: "downloading", downloaded: ++downloaded, goal: totalFavorites }
+					continue
+				}
 
-		for (let postId of postIds) {
-			if (alreadyDownloaded.includes(postId)) {
+				currentPostId = postId
+				const data = await getPostData(postId)
+				await upload(data)
 				progressState.val = { state: "downloading", downloaded: ++downloaded, goal: totalFavorites }
-				continue
 			}
+			currentPostId = null
+			await checkpointSyncSession(sessionId, pid, downloaded)
+		} while (pid > 0)
+	} catch (error: unknown) {
+		await reportSyncError(sessionId, currentPostId, String(error))
+		throw error
+	}
 
-			const data = await getPostData(postId)
-			await upload(data)
-			progressState.val = { state: "downloading", downloaded: ++downloaded, goal: totalFavorites }
-		}
-	} while (pid > 0)
+	const redownloaded = await redownloadBroken()
+	const unfavorited = await finishReconcile(reconcileSession)
+	await finishSyncSession(sessionId)
+	const unfavoritedMessage = unfavorited.length
+		? ` ${unfavorited.length} downloaded posts are no longer favorited.`
+		: ""
+	progressState.val = {
+		state: "done",
+		message: `Synced ${downloaded} favorites.${redownloadMessage(redownloaded)}${unfavoritedMessage}`,
+	}
+}
+
+// The server replaces the file of posts it flagged as missing or broken when they are uploaded again
+async function redownloadBroken(): Promise<number> {
+	const postIds = await getRedownloadIds()
+	for (let postId of postIds) {
+		const data = await getPostData(postId)
+		await upload(data)
+	}
+	return postIds.length
+}
 
-	progressState.val = { state: "done", message: `Synced ${downloaded} favorites.` }
+function redownloadMessage(redownloaded: number): string {
+	return redownloaded ? ` Re-downloaded ${redownloaded} broken posts.` : ""
 }
 
 export async function syncSingle(postId: number) {
@@ -86,7 +151,7 @@ export function getUserId() {
 }
 
 export async function getUserFavoritesCount(userId: number) {
-	const profileDOM = await fetchDocument(`https://rule34.xxx/index.php?page=account&s=profile&id=${userId}`)
+	const profileDOM = await fetchDocument(`/index.php?page=account&s=profile&id=${userId}`)
 	const favoritesCountString = profileDOM.querySelector(
 		`a[href="index.php?page=favorites&s=view&id=${userId}"]`,
 	)?.textContent
@@ -100,7 +165,7 @@ export function getPostIds(favoritesPage: Document): number[] {
 }
 
 function getFavoritesPage(userId: number, pid: number) {
-	return fetchDocument(`https://rule34.xxx/index.php?page=favorites&s=view&id=${userId}&pid=${pid}`)
+	return fetchDocument(`/index.php?page=favorites&s=view&id=${userId}&pid=${pid}`)
 }
 
 // Single post processing
@@ -108,6 +173,16 @@ export interface PostData {
 	id: number
 	image: Blob
 	tags: Tag[]
+	metadata: PostMetadata
+}
+
+// Sent along with uploads under the same names
+export interface PostMetadata {
+	rating?: string
+	score?: number
+	source?: string
+	parentId?: number
+	md5?: string
 }
 
 export interface PostInfo {
@@ -140,6 +215,7 @@ export async function getPostData(postId: number, fetchBlob: boolean = true): Pr
 			id: postId,
 			image: await fetchImage(imageUrl),
 			tags,
+			metadata: getPostMetadata(postDOM, imageUrl),
 		}
 	} else {
 		return {
@@ -160,6 +236,34 @@ function getImageUrl(postDOM: Document): string {
 	return url
 }
 
+// Original files are named after their md5, which lets the server notice truncated downloads
+const MD5_FILE_NAME = /\/([0-9a-f]{32})\.\w+(\?|$)/
+
+function getPostMetadata(postDOM: Document, imageUrl: string): PostMetadata {
+	const statistics = Array.from(postDOM.querySelectorAll("#stats li"))
+	const statistic = (label: string) => statistics.find((li) => (li.textContent ?? "").trim().startsWith(`${label}:`))
+
+	const rating = statistic("Rating")?.textContent?.split(":")[1]?.trim()
+	const score = Number.parseInt(statistic("Score")?.querySelector("span")?.textContent ?? "", 10)
+	const sourceElement = statistic("Source")
+	const source =
+		sourceElement?.querySelector("a")?.getAttribute("href") ??
+		sourceElement?.textContent?.trim().substring("Source:".length).trim()
+	const parentLink = Array.from(postDOM.querySelectorAll(".status-notice"))
+		.find((notice) => (notice.textContent ?? "").includes("parent"))
+		?.querySelector('a[href*="page=post&s=view&id="]')
+		?.getAttribute("href")
+	const parentId = Number.parseInt(new URL(parentLink ?? "", location.href).searchParams.get("id") ?? "", 10)
+
+	return {
+		rating: rating || undefined,
+		score: Number.isNaN(score) ? undefined : score,
+		source: source || undefined,
+		parentId: parentLink && !Number.isNaN(parentId) ? parentId : undefined,
+		md5: imageUrl.match(MD5_FILE_NAME)?.[1],
+	}
+}
+
 const TAG_KIND_CLASS = "tag-type-"
 function tagFilter(tag: { name: string; kind: string | undefined }): tag is Tag {
 	return Boolean(tag.name && tag.kind && isTagKind(tag.kind))